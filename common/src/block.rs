@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use ethers::prelude::{BlockNumber, Middleware};
+
+#[derive(Debug)]
+pub enum ResolveError<E> {
+    Middleware(E),
+    /// 节点没有返回这个区块
+    MissingBlock(u64),
+    /// 节点没有返回最新区块
+    MissingHead,
+}
+
+impl<E: Display> Display for ResolveError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveError::Middleware(e) => write!(f, "{e}"),
+            ResolveError::MissingBlock(number) => write!(f, "block {number} not found"),
+            ResolveError::MissingHead => write!(f, "latest block not found"),
+        }
+    }
+}
+
+impl<E: Error> Error for ResolveError<E> {}
+
+/// 区块时间戳缓存, 满了淘汰最久没用过的一个, 二分查找上层的区块每次都会用到, 不会被挤掉
+struct Cache {
+    /// 区块号 -> (时间戳, 最后使用的序号)
+    entries: HashMap<u64, (u64, u64)>,
+    tick: u64,
+}
+
+impl Cache {
+    const SIZE: usize = 256;
+
+    fn get(&mut self, number: u64) -> Option<u64> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(&number)?;
+        entry.1 = self.tick;
+        Some(entry.0)
+    }
+
+    fn insert(&mut self, number: u64, timestamp: u64) {
+        self.tick += 1;
+        if self.entries.len() >= Self::SIZE && !self.entries.contains_key(&number) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(number, _)| *number);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(number, (timestamp, self.tick));
+    }
+}
+
+/// 按时间戳二分查找区块号, 缓存查过的区块时间戳, 同一区间的上下界共享大部分查找路径
+pub struct BlockResolver<M> {
+    provider: Arc<M>,
+    cache: Mutex<Cache>,
+}
+
+impl<M: Middleware> BlockResolver<M> {
+    pub fn new(provider: Arc<M>) -> Self {
+        Self {
+            provider,
+            cache: Mutex::new(Cache {
+                entries: HashMap::with_capacity(Cache::SIZE),
+                tick: 0,
+            }),
+        }
+    }
+
+    pub async fn latest(&self) -> Result<(u64, u64), ResolveError<M::Error>> {
+        let block = self
+            .provider
+            .get_block(BlockNumber::Latest)
+            .await
+            .map_err(ResolveError::Middleware)?
+            .ok_or(ResolveError::MissingHead)?;
+        let number = block.number.ok_or(ResolveError::MissingHead)?.as_u64();
+        let timestamp = block.timestamp.as_u64();
+        self.remember(number, timestamp);
+        Ok((number, timestamp))
+    }
+
+    pub async fn timestamp(&self, number: u64) -> Result<u64, ResolveError<M::Error>> {
+        if let Some(timestamp) = self.cache.lock().unwrap().get(number) {
+            return Ok(timestamp);
+        }
+        let timestamp = self
+            .provider
+            .get_block(number)
+            .await
+            .map_err(ResolveError::Middleware)?
+            .ok_or(ResolveError::MissingBlock(number))?
+            .timestamp
+            .as_u64();
+        self.remember(number, timestamp);
+        Ok(timestamp)
+    }
+
+    /// first block with `block.timestamp >= timestamp`, `latest + 1` if the chain has not got there yet
+    pub async fn first_block_at(&self, timestamp: u64) -> Result<u64, ResolveError<M::Error>> {
+        let head = self.latest().await?;
+        self.search(head, timestamp).await
+    }
+
+    /// 在`head`及之前的区块里查找
+    async fn search(
+        &self,
+        (latest, latest_timestamp): (u64, u64),
+        timestamp: u64,
+    ) -> Result<u64, ResolveError<M::Error>> {
+        if latest_timestamp < timestamp {
+            return Ok(latest + 1);
+        }
+        let (mut lo, mut hi) = (0_u64, latest);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.timestamp(mid).await? < timestamp {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(lo)
+    }
+
+    /// blocks with `start <= block.timestamp < end`, `None` if there is no such block yet;
+    /// 上下界用同一个链头, 不然查找中间出了新块时两个边界对不上
+    pub async fn block_range(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> Result<Option<(u64, u64)>, ResolveError<M::Error>> {
+        let head = self.latest().await?;
+        let from_block = self.search(head, start).await?;
+        let to_block = self.search(head, end).await?;
+        if to_block <= from_block {
+            return Ok(None);
+        }
+        Ok(Some((from_block, to_block - 1)))
+    }
+
+    fn remember(&self, number: u64, timestamp: u64) {
+        self.cache.lock().unwrap().insert(number, timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use ethers::prelude::{Block, Provider, TxHash};
+    use ethers::providers::{JsonRpcClient, MockError};
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use serde_json::Value;
+
+    use super::*;
+
+    /// 按请求的区块号回答eth_getBlockByNumber, 记下请求最新区块的次数
+    #[derive(Debug)]
+    struct Chain {
+        timestamps: Vec<u64>,
+        heads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl JsonRpcClient for Chain {
+        type Error = MockError;
+
+        async fn request<T: Serialize + Send + Sync, R: DeserializeOwned>(
+            &self,
+            method: &str,
+            params: T,
+        ) -> Result<R, MockError> {
+            assert_eq!(method, "eth_getBlockByNumber");
+            let number = match &serde_json::to_value(params)?[0] {
+                Value::String(tag) if tag == "latest" => {
+                    self.heads.fetch_add(1, Ordering::SeqCst);
+                    self.timestamps.len() - 1
+                }
+                Value::String(hex) => {
+                    usize::from_str_radix(hex.trim_start_matches("0x"), 16).unwrap()
+                }
+                other => panic!("unexpected block {other}"),
+            };
+            let block = self
+                .timestamps
+                .get(number)
+                .map(|timestamp| block(number as u64, *timestamp));
+            Ok(serde_json::from_value(serde_json::to_value(block)?)?)
+        }
+    }
+
+    fn block(number: u64, timestamp: u64) -> Block<TxHash> {
+        Block {
+            number: Some(number.into()),
+            timestamp: timestamp.into(),
+            ..Default::default()
+        }
+    }
+
+    /// 区块0到5的时间戳, 3秒一个块, 中间有两个块同一秒
+    fn resolver() -> BlockResolver<Provider<Chain>> {
+        BlockResolver::new(Arc::new(Provider::new(Chain {
+            timestamps: vec![100, 103, 106, 106, 109, 112],
+            heads: AtomicUsize::new(0),
+        })))
+    }
+
+    #[tokio::test]
+    async fn first_block_at_bounds() {
+        let resolver = resolver();
+        assert_eq!(resolver.first_block_at(0).await.unwrap(), 0);
+        assert_eq!(resolver.first_block_at(100).await.unwrap(), 0);
+        assert_eq!(resolver.first_block_at(101).await.unwrap(), 1);
+        assert_eq!(resolver.first_block_at(106).await.unwrap(), 2);
+        assert_eq!(resolver.first_block_at(107).await.unwrap(), 4);
+        assert_eq!(resolver.first_block_at(112).await.unwrap(), 5);
+        assert_eq!(resolver.first_block_at(113).await.unwrap(), 6);
+    }
+
+    #[tokio::test]
+    async fn block_range_is_start_inclusive_end_exclusive() {
+        let resolver = resolver();
+        assert_eq!(resolver.block_range(103, 109).await.unwrap(), Some((1, 3)));
+        assert_eq!(resolver.block_range(104, 110).await.unwrap(), Some((2, 4)));
+        assert_eq!(resolver.block_range(106, 107).await.unwrap(), Some((2, 3)));
+        assert_eq!(resolver.block_range(0, 1000).await.unwrap(), Some((0, 5)));
+    }
+
+    #[tokio::test]
+    async fn block_range_outside_the_chain() {
+        let resolver = resolver();
+        // 创世块之前
+        assert_eq!(resolver.block_range(0, 100).await.unwrap(), None);
+        assert_eq!(resolver.block_range(0, 101).await.unwrap(), Some((0, 0)));
+        // 链头之后
+        assert_eq!(resolver.block_range(113, 200).await.unwrap(), None);
+        assert_eq!(resolver.block_range(112, 200).await.unwrap(), Some((5, 5)));
+        // 空窗口
+        assert_eq!(resolver.block_range(107, 109).await.unwrap(), None);
+        assert_eq!(resolver.block_range(109, 109).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn block_range_fetches_the_head_once() {
+        let resolver = resolver();
+        resolver.block_range(101, 110).await.unwrap();
+        assert_eq!(
+            resolver
+                .provider
+                .as_ref()
+                .as_ref()
+                .heads
+                .load(Ordering::SeqCst),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn window_after_head_only_asks_for_the_head() {
+        let (provider, mock) = Provider::mocked();
+        mock.push(block(7, 1000)).unwrap();
+        let resolver = BlockResolver::new(Arc::new(provider));
+        assert_eq!(resolver.block_range(2000, 3000).await.unwrap(), None);
+        mock.assert_request("eth_getBlockByNumber", ("latest", false))
+            .unwrap();
+        assert!(mock
+            .assert_request("eth_getBlockByNumber", ("latest", false))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_head_is_reported() {
+        let (provider, mock) = Provider::mocked();
+        mock.push(Value::Null).unwrap();
+        let resolver = BlockResolver::new(Arc::new(provider));
+        assert!(matches!(
+            resolver.block_range(0, 1).await,
+            Err(ResolveError::MissingHead)
+        ));
+    }
+
+    #[test]
+    fn cache_evicts_the_least_recently_used() {
+        let mut cache = Cache {
+            entries: HashMap::new(),
+            tick: 0,
+        };
+        for number in 0..Cache::SIZE as u64 {
+            cache.insert(number, number * 3);
+        }
+        assert_eq!(cache.get(0), Some(0));
+        cache.insert(1000, 1);
+        assert_eq!(cache.entries.len(), Cache::SIZE);
+        assert_eq!(cache.get(0), Some(0));
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(1000), Some(1));
+    }
+}
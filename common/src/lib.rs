@@ -2,18 +2,24 @@
 pub use self::db::create_pool;
 #[cfg(feature = "file-logger")]
 pub use self::logger::init_file_logger;
-pub use self::logger::init_logger;
 #[cfg(feature = "tracing")]
 pub use self::logger::init_tracing_logger;
+pub use self::logger::{init_logger, logger_builder};
 pub use self::setting::Setting;
 
+#[cfg(feature = "web3")]
+pub mod block;
 #[cfg(feature = "web3")]
 pub mod erc20;
+pub mod message;
+pub mod metrics;
 #[cfg(feature = "pg-with-model")]
 pub mod model;
 #[cfg(feature = "pulsar")]
 pub mod schema;
 mod setting;
+#[cfg(feature = "shutdown")]
+pub mod shutdown;
 
 #[cfg(feature = "pg")]
 pub mod db {
@@ -47,7 +53,13 @@ pub mod logger {
     // RUST_LOG=error, ["OFF", "ERROR", "WARN", "INFO", "DEBUG", "TRACE"]
     // RUST_LOG=error,hello=warn
     pub fn init_logger() {
-        env_logger::builder()
+        logger_builder().init();
+    }
+
+    /// init_logger的格式和级别, 需要包一层再安装时用它build
+    pub fn logger_builder() -> env_logger::Builder {
+        let mut builder = env_logger::builder();
+        builder
             .filter_level(LevelFilter::Debug)
             .format(|buf, record| {
                 let mut level_style = buf.default_level_style(record.level());
@@ -64,8 +76,8 @@ pub mod logger {
                     record.level(),
                     record.args()
                 )
-            })
-            .init();
+            });
+        builder
     }
 
     #[cfg(feature = "file-logger")]
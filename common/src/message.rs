@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+/// TokenTransfer事件里message字段的json, 不依赖pulsar, 扫链时也用它解析
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenMessageArg {
+    pub from_user_id: String,
+    pub to_user_id: String,
+    pub coin_code: String,
+    pub point: f32,
+    pub tag_id: String,
+    pub store_id: String,
+    pub gen_time: String,
+    #[serde(default = "default_pay_type")]
+    pub pay_type: String,
+    pub trxn_result: String,
+    pub trxn_type: Option<String>,
+}
+
+fn default_pay_type() -> String {
+    "xxPay".to_string()
+}
@@ -0,0 +1,184 @@
+//! 运行指标, 开启`metrics` feature时通过http的`/metrics`给prometheus抓取,
+//! 没开启时这些函数什么都不做, 调用的地方不用加cfg
+
+#[cfg(not(feature = "metrics"))]
+pub use self::noop::*;
+#[cfg(feature = "metrics")]
+pub use self::prom::*;
+
+#[cfg(feature = "metrics")]
+mod prom {
+    use std::convert::Infallible;
+    use std::sync::LazyLock;
+    use std::time::Duration;
+
+    use http_body_util::Full;
+    use hyper::body::{Bytes, Incoming};
+    use hyper::header::CONTENT_TYPE;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use log::{info, warn};
+    use prometheus::{
+        register_histogram_vec, register_int_counter, register_int_counter_vec,
+        register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec,
+        TextEncoder,
+    };
+    use tokio::net::TcpListener;
+
+    static RANGES_PROCESSED: LazyLock<IntCounter> = LazyLock::new(|| {
+        register_int_counter!("ranges_processed_total", "block ranges scanned and saved").unwrap()
+    });
+    static RANGES_FAILED: LazyLock<IntCounter> = LazyLock::new(|| {
+        register_int_counter!("ranges_failed_total", "block ranges given up after retries").unwrap()
+    });
+    static LOGS_FOUND: LazyLock<IntCounter> = LazyLock::new(|| {
+        register_int_counter!("logs_found_total", "logs found in the scanned ranges").unwrap()
+    });
+    static RPC_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+        register_histogram_vec!(
+            "rpc_request_duration_seconds",
+            "rpc request latency including retries",
+            &["endpoint"],
+            vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+        )
+        .unwrap()
+    });
+    static RPC_RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+        register_int_counter_vec!("rpc_retries_total", "rpc request retries", &["endpoint"])
+            .unwrap()
+    });
+    static DB_ROWS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+        register_int_counter_vec!(
+            "db_rows_inserted_total",
+            "rows written to the db",
+            &["table"]
+        )
+        .unwrap()
+    });
+    static PULSAR_SENT: LazyLock<IntCounter> = LazyLock::new(|| {
+        register_int_counter!("pulsar_messages_sent_total", "messages acked by pulsar").unwrap()
+    });
+    static PULSAR_FAILED: LazyLock<IntCounter> = LazyLock::new(|| {
+        register_int_counter!(
+            "pulsar_messages_failed_total",
+            "messages pulsar failed to take"
+        )
+        .unwrap()
+    });
+    static CHANNEL_BACKLOG: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+        register_int_gauge_vec!(
+            "channel_backlog",
+            "messages waiting in an internal channel",
+            &["channel"]
+        )
+        .unwrap()
+    });
+
+    pub fn range_done(logs: usize) {
+        RANGES_PROCESSED.inc();
+        LOGS_FOUND.inc_by(logs as u64);
+    }
+
+    pub fn range_failed() {
+        RANGES_FAILED.inc();
+    }
+
+    pub fn rpc_request(endpoint: &str, elapsed: Duration) {
+        RPC_LATENCY
+            .with_label_values(&[endpoint])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn rpc_retry(endpoint: &str) {
+        RPC_RETRIES.with_label_values(&[endpoint]).inc();
+    }
+
+    pub fn rows_inserted(table: &str, rows: usize) {
+        DB_ROWS.with_label_values(&[table]).inc_by(rows as u64);
+    }
+
+    pub fn pulsar_sent(messages: usize) {
+        PULSAR_SENT.inc_by(messages as u64);
+    }
+
+    pub fn pulsar_failed(messages: usize) {
+        PULSAR_FAILED.inc_by(messages as u64);
+    }
+
+    pub fn channel_backlog(channel: &str, len: usize) {
+        CHANNEL_BACKLOG
+            .with_label_values(&[channel])
+            .set(len as i64);
+    }
+
+    /// 绑定地址后在后台处理请求, 任何路径都返回指标
+    pub async fn serve(addr: &str) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("metrics on http://{}/metrics", listener.local_addr()?);
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("metrics accept error: {e}");
+                        continue;
+                    }
+                };
+                tokio::spawn(async move {
+                    let service =
+                        service_fn(|_: Request<Incoming>| async { Ok::<_, Infallible>(render()) });
+                    if let Err(e) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        warn!("metrics connection error: {e}");
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn render() -> Response<Full<Bytes>> {
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+        match encoder.encode(&prometheus::gather(), &mut buf) {
+            Ok(()) => Response::builder()
+                .header(CONTENT_TYPE, encoder.format_type())
+                .body(Full::new(Bytes::from(buf)))
+                .unwrap(),
+            Err(e) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Full::new(Bytes::from(e.to_string())))
+                .unwrap(),
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod noop {
+    use std::time::Duration;
+
+    pub fn range_done(_logs: usize) {}
+
+    pub fn range_failed() {}
+
+    pub fn rpc_request(_endpoint: &str, _elapsed: Duration) {}
+
+    pub fn rpc_retry(_endpoint: &str) {}
+
+    pub fn rows_inserted(_table: &str, _rows: usize) {}
+
+    pub fn pulsar_sent(_messages: usize) {}
+
+    pub fn pulsar_failed(_messages: usize) {}
+
+    pub fn channel_backlog(_channel: &str, _len: usize) {}
+
+    pub async fn serve(addr: &str) -> std::io::Result<()> {
+        log::warn!("built without the metrics feature, {addr} is not served");
+        Ok(())
+    }
+}
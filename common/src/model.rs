@@ -1,12 +1,15 @@
 use serde::{Deserialize, Serialize};
-use std::error::Error;
-use std::fmt::{Display, Formatter};
-use std::str::FromStr;
 
 use chrono::{DateTime, Utc};
 pub use postgres_from_row::FromRow;
-use tokio_postgres::types::private::BytesMut;
-use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+#[cfg(feature = "pg-with-enum")]
+use {
+    std::error::Error,
+    std::fmt::{Display, Formatter},
+    std::str::FromStr,
+    tokio_postgres::types::private::BytesMut,
+    tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type},
+};
 #[cfg(feature = "pg-with-enum")]
 use {
     duplicate::duplicate_item,
@@ -1,29 +1,14 @@
+use std::collections::HashSet;
+
 use log::debug;
 use pulsar::{
     producer, proto, DeserializeMessage, Error as PulsarError, Payload, SerializeMessage,
 };
 use schemars::{schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct TokenMessageArg {
-    pub from_user_id: String,
-    pub to_user_id: String,
-    pub coin_code: String,
-    pub point: f32,
-    pub tag_id: String,
-    pub store_id: String,
-    pub gen_time: String,
-    #[serde(default = "default_pay_type")]
-    pub pay_type: String,
-    pub trxn_result: String,
-    pub trxn_type: Option<String>,
-}
+use serde_json::{json, Value};
 
-fn default_pay_type() -> String {
-    "xxPay".to_string()
-}
+pub use crate::message::TokenMessageArg;
 
 impl TokenMessageArg {
     pub fn make_msg_with_ext(&self, ext_json: String) -> Msg {
@@ -72,25 +57,112 @@ impl DeserializeMessage for Msg {
     }
 }
 
+/// transfer和mint, 金额是十进制字符串, 地址是0x开头的hex
+#[derive(JsonSchema, Serialize, Deserialize, Debug, Clone)]
+pub struct TransferMsg {
+    pub to: String,
+    pub amount: String,
+}
+
+#[derive(JsonSchema, Serialize, Deserialize, Debug, Clone)]
+pub struct TransferFromMsg {
+    pub from: String,
+    pub to: String,
+    pub amount: String,
+}
+
+/// 每个地址都mint同样的金额
+#[derive(JsonSchema, Serialize, Deserialize, Debug, Clone)]
+pub struct BatchMintMsg {
+    pub recipients: Vec<String>,
+    pub amount: String,
+}
+
+/// approve, increaseAllowance和decreaseAllowance, 后两个的amount是变化量
+#[derive(JsonSchema, Serialize, Deserialize, Debug, Clone)]
+pub struct ApproveMsg {
+    pub spender: String,
+    pub amount: String,
+}
+
+/// 所有会改变状态的代币调用, json里用`kind`区分, 同时放在消息属性`kind`里方便按类型过滤;
+/// token_transfer的字段和原来的Msg一样, 只多了`kind`
+#[derive(JsonSchema, Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CallMsg {
+    TokenTransfer(Msg),
+    Transfer(TransferMsg),
+    TransferFrom(TransferFromMsg),
+    Mint(TransferMsg),
+    BatchMint(BatchMintMsg),
+    Approve(ApproveMsg),
+    IncreaseAllowance(ApproveMsg),
+    DecreaseAllowance(ApproveMsg),
+}
+
+impl CallMsg {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CallMsg::TokenTransfer(_) => "token_transfer",
+            CallMsg::Transfer(_) => "transfer",
+            CallMsg::TransferFrom(_) => "transfer_from",
+            CallMsg::Mint(_) => "mint",
+            CallMsg::BatchMint(_) => "batch_mint",
+            CallMsg::Approve(_) => "approve",
+            CallMsg::IncreaseAllowance(_) => "increase_allowance",
+            CallMsg::DecreaseAllowance(_) => "decrease_allowance",
+        }
+    }
+}
+
+impl SerializeMessage for CallMsg {
+    fn serialize_message(input: Self) -> Result<producer::Message, PulsarError> {
+        let kind = input.kind().to_string();
+        let payload = serde_json::to_vec(&input).map_err(|e| PulsarError::Custom(e.to_string()))?;
+        Ok(producer::Message {
+            payload,
+            properties: [("kind".to_string(), kind)].into(),
+            ..Default::default()
+        })
+    }
+}
+
+impl DeserializeMessage for CallMsg {
+    type Output = Result<CallMsg, serde_json::Error>;
+
+    fn deserialize_message(payload: &Payload) -> Self::Output {
+        serde_json::from_slice(&payload.data)
+    }
+}
+
 pub trait PulsarSchema
 where
     Self: SerializeMessage,
     Self: DeserializeMessage,
+    Self: JsonSchema,
 {
     fn pulsar_json_schema() -> proto::Schema {
-        let schema = schema_for!(Msg);
+        let schema = schema_for!(Self);
         let schema_map = serde_json::to_value(schema).unwrap();
-        let fields: Vec<_> = schema_map["properties"]
-            .as_object()
-            .unwrap()
+        // 带标签的enum没有顶层的properties, 合并每个分支的字段, 同名字段取第一次出现的;
+        // 不是每个分支都有的字段可以为null, 默认null
+        let variants = match schema_map["oneOf"].as_array() {
+            Some(variants) => variants.iter().collect(),
+            None => vec![&schema_map],
+        };
+        let in_all = |k: &str| variants.iter().all(|v| v["properties"].get(k).is_some());
+        let mut names = HashSet::new();
+        let fields: Vec<_> = variants
             .iter()
+            .flat_map(|variant| variant["properties"].as_object().unwrap())
+            .filter(|(k, _)| names.insert(k.as_str()))
             .map(|(k, v)| {
-                let t = if let Some(vf) = v.get("format") {
-                    vf
+                let t = field_type(v);
+                if in_all(k) {
+                    json!({"name":k,"type": t})
                 } else {
-                    v.get("type").unwrap()
-                };
-                json!({"name":k,"type": t})
+                    json!({"name":k,"type": nullable(t),"default": null})
+                }
             })
             .collect();
         let myname = std::any::type_name::<Self>().split("::").last().unwrap();
@@ -107,4 +179,85 @@ where
     }
 }
 
-impl<T> PulsarSchema for T where T: SerializeMessage + DeserializeMessage {}
+fn field_type(v: &Value) -> Value {
+    if let Some(vf) = v.get("format") {
+        return vf.clone();
+    }
+    let t = v.get("type").unwrap();
+    if t == "array" {
+        json!({"type": "array", "items": field_type(&v["items"])})
+    } else {
+        t.clone()
+    }
+}
+
+/// null放在第一个, 默认值才能是null
+fn nullable(t: Value) -> Value {
+    let mut types = vec![Value::from("null")];
+    match t {
+        Value::Array(ts) => types.extend(ts.into_iter().filter(|t| t != "null")),
+        t => types.push(t),
+    }
+    Value::Array(types)
+}
+
+impl<T> PulsarSchema for T where T: SerializeMessage + DeserializeMessage + JsonSchema {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields<T: PulsarSchema>() -> Vec<Value> {
+        let schema: Value = serde_json::from_slice(&T::pulsar_json_schema().schema_data).unwrap();
+        schema["fields"].as_array().unwrap().clone()
+    }
+
+    fn field<'a>(fields: &'a [Value], name: &str) -> &'a Value {
+        fields.iter().find(|f| f["name"] == name).unwrap()
+    }
+
+    #[test]
+    fn msg_schema_is_unchanged() {
+        let fields = fields::<Msg>();
+        assert_eq!(fields.len(), 9);
+        assert_eq!(
+            field(&fields, "point"),
+            &json!({"name": "point", "type": "float"})
+        );
+        assert_eq!(
+            field(&fields, "retry_info"),
+            &json!({"name": "retry_info", "type": ["string", "null"]})
+        );
+        assert!(fields.iter().all(|f| f.get("default").is_none()));
+    }
+
+    #[test]
+    fn call_msg_schema() {
+        let fields = fields::<CallMsg>();
+        assert_eq!(fields.len(), 15);
+        assert_eq!(
+            field(&fields, "kind"),
+            &json!({"name": "kind", "type": "string"})
+        );
+        assert_eq!(
+            field(&fields, "recipients"),
+            &json!({
+                "name": "recipients",
+                "type": ["null", {"type": "array", "items": "string"}],
+                "default": null
+            })
+        );
+        assert_eq!(
+            field(&fields, "coin_code"),
+            &json!({"name": "coin_code", "type": ["null", "string"], "default": null})
+        );
+        assert_eq!(
+            field(&fields, "retry_info"),
+            &json!({"name": "retry_info", "type": ["null", "string"], "default": null})
+        );
+        assert_eq!(
+            field(&fields, "amount"),
+            &json!({"name": "amount", "type": ["null", "string"], "default": null})
+        );
+    }
+}
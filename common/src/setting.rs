@@ -8,6 +8,8 @@ pub struct Setting {
     pub explorer_db: String,
     pub db: String,
     pub rpc_list: Vec<String>,
+    /// prometheus抓取地址, 比如`0.0.0.0:9100`, 不设置就不开
+    pub metrics_addr: Option<String>,
 }
 
 impl Setting {
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use log::warn;
+use tokio::sync::watch;
+
+/// 收到SIGINT或SIGTERM后置位, 让任务停止接新的工作并把手上的做完,
+/// 再收到一次信号直接退出
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn listen() -> Self {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            let name = signal().await;
+            warn!("received {name}, shutting down, send it again to exit immediately");
+            let _ = tx.send(true);
+            let name = signal().await;
+            warn!("received {name} again, exit");
+            std::process::exit(130);
+        });
+        Self { rx }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    pub async fn wait(&mut self) {
+        // 发送端只在进程退出时才会drop
+        let _ = self.rx.wait_for(|triggered| *triggered).await;
+    }
+
+    /// 睡`duration`, 期间收到信号就提前返回
+    pub async fn sleep(&mut self, duration: Duration) {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = self.wait() => {}
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "ctrl-c"
+}
@@ -0,0 +1,191 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerOptions {
+    /// 连续这么多次网络错误后断开
+    pub failures: u32,
+    /// 断开后多久放一个探测请求过去
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerOptions {
+    fn default() -> Self {
+        Self {
+            failures: 3,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// 探测请求发出的时间, 超过cooldown还没结果就当它丢了, 再放一个
+    HalfOpen {
+        probe_at: Instant,
+    },
+}
+
+/// 一个rpc节点的熔断器, 只统计网络错误, json-rpc错误说明节点是通的
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    options: BreakerOptions,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, options: BreakerOptions) -> Self {
+        Self {
+            name: name.to_string(),
+            options,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// 现在发请求会不会被放行, 不改变状态
+    pub fn available(&self) -> bool {
+        let now = Instant::now();
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => true,
+            State::Open { until } => now >= until,
+            State::HalfOpen { probe_at } => now >= probe_at + self.options.cooldown,
+        }
+    }
+
+    /// 断开期间直接拒绝, 冷却结束后只放行一个探测请求
+    pub fn try_acquire(&self) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                info!("rpc {} circuit half-open, probing", self.name);
+                *state = State::HalfOpen { probe_at: now };
+                true
+            }
+            State::HalfOpen { probe_at } if now >= probe_at + self.options.cooldown => {
+                *state = State::HalfOpen { probe_at: now };
+                true
+            }
+            State::Open { .. } | State::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn on_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            info!("rpc {} circuit closed", self.name);
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub fn on_failure(&self) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { failures } if failures + 1 < self.options.failures => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                };
+            }
+            State::Closed { failures } => {
+                warn!(
+                    "rpc {} circuit open for {:?} after {} failures",
+                    self.name,
+                    self.options.cooldown,
+                    failures + 1
+                );
+                *state = State::Open {
+                    until: now + self.options.cooldown,
+                };
+            }
+            State::HalfOpen { .. } => {
+                warn!(
+                    "rpc {} probe failed, circuit open for {:?}",
+                    self.name, self.options.cooldown
+                );
+                *state = State::Open {
+                    until: now + self.options.cooldown,
+                };
+            }
+            // 断开前发出的请求晚到的失败, 不延长冷却
+            State::Open { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(cooldown_ms: u64) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            BreakerOptions {
+                failures: 2,
+                cooldown: Duration::from_millis(cooldown_ms),
+            },
+        )
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let b = breaker(60_000);
+        b.on_failure();
+        b.on_success();
+        b.on_failure();
+        assert!(b.try_acquire());
+        b.on_failure();
+        assert!(!b.available());
+        assert!(!b.try_acquire());
+    }
+
+    #[test]
+    fn half_open_lets_one_probe_through() {
+        let b = breaker(20);
+        b.on_failure();
+        b.on_failure();
+        assert!(!b.try_acquire());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(b.available());
+        assert!(b.try_acquire());
+        assert!(!b.try_acquire());
+        b.on_success();
+        assert!(b.try_acquire());
+        assert!(b.try_acquire());
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let b = breaker(20);
+        b.on_failure();
+        b.on_failure();
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(b.try_acquire());
+        b.on_failure();
+        assert!(!b.try_acquire());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(b.try_acquire());
+    }
+
+    #[test]
+    fn lost_probe_is_replaced_after_cooldown() {
+        let b = breaker(20);
+        b.on_failure();
+        b.on_failure();
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(b.try_acquire());
+        assert!(!b.available());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(b.try_acquire());
+    }
+}
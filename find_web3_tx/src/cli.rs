@@ -0,0 +1,362 @@
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use ethers::prelude::Address;
+
+use crate::breaker::BreakerOptions;
+use crate::export::ExportFormat;
+use crate::limit::RpcLimit;
+use crate::pool::PoolOptions;
+use crate::retry::RetrySettings;
+use crate::schema::EventSet;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Find TokenTransfer logs of erc20 tokens")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Scan the TokenTransfer logs of a day or a date range into sqlite
+    Scan(ScanArgs),
+    /// Export the transactions of an existing sqlite output file
+    Export(ExportArgs),
+    /// Keep indexing new TokenTransfer logs from the last checkpoint
+    Follow(FollowArgs),
+    /// Compare the scanned logs of a window with the transaction_pool table
+    Reconcile(ReconcileArgs),
+}
+
+/// 按本地日期指定的时间窗口
+#[derive(Args, Debug)]
+pub struct Window {
+    /// local day, e.g. 2023-11-28
+    #[arg(short, long, required_unless_present = "from", conflicts_with_all = ["from", "to"])]
+    pub date: Option<NaiveDate>,
+    /// first local day of the range
+    #[arg(long, requires = "to")]
+    pub from: Option<NaiveDate>,
+    /// last local day of the range, inclusive
+    #[arg(long, requires = "from")]
+    pub to: Option<NaiveDate>,
+    /// timezone of the dates, IANA name
+    #[arg(long, default_value = "Asia/Tokyo")]
+    pub tz: Tz,
+    /// minutes of blocks scanned before the first day
+    #[arg(long, default_value_t = 60)]
+    pub margin_before: u32,
+    /// minutes of blocks scanned after the last day, for logs of the day mined after midnight
+    #[arg(long, default_value_t = 180)]
+    pub margin_after: u32,
+}
+
+/// 合约地址和显示用的label, label默认是地址本身
+#[derive(Debug, Clone)]
+pub struct TokenSpec {
+    pub label: String,
+    pub address: Address,
+}
+
+impl FromStr for TokenSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, address) = match s.split_once('=') {
+            Some((label, address)) => (Some(label.trim()), address.trim()),
+            None => (None, s.trim()),
+        };
+        let address: Address = address
+            .parse()
+            .map_err(|e| format!("invalid address {address:?}: {e}"))?;
+        let label = match label {
+            Some("") => return Err(format!("empty label in {s:?}")),
+            Some(label) => label.to_string(),
+            None => format!("{address:?}"),
+        };
+        Ok(Self { label, address })
+    }
+}
+
+/// 地址和label, 传给Store记录
+pub fn token_pairs(tokens: &[TokenSpec]) -> Vec<(Address, String)> {
+    tokens
+        .iter()
+        .map(|t| (t.address, t.label.clone()))
+        .collect()
+}
+
+/// rpc_list节点池的健康检查参数
+#[derive(Args, Debug)]
+pub struct PoolArgs {
+    /// consecutive transport errors before the circuit breaker of an rpc endpoint opens
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub breaker_failures: u32,
+    /// seconds an open circuit rejects requests before letting a probe through
+    #[arg(long, default_value_t = 30)]
+    pub breaker_cooldown: u64,
+    /// request limits of the rpc endpoints whose url contains PATTERN, `*` matches every endpoint,
+    /// e.g. `infura.io=10,burst=20,concurrency=4,daily=100000`, repeat for more endpoints;
+    /// the daily count is kept in memory and starts from zero on every run, so leave room for
+    /// requests already made today when a scan is restarted
+    #[arg(
+        long = "rpc-limit",
+        value_name = "PATTERN=RATE[,burst=N][,concurrency=N][,daily=N]"
+    )]
+    pub rpc_limits: Vec<RpcLimit>,
+    /// json file with the retry rules, backoff and per-provider profiles of the rpc endpoints
+    #[arg(long)]
+    pub retry_config: Option<PathBuf>,
+}
+
+impl PoolArgs {
+    pub fn options(&self) -> anyhow::Result<PoolOptions> {
+        let retry = match &self.retry_config {
+            Some(path) => {
+                RetrySettings::load(path).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?
+            }
+            None => RetrySettings::default(),
+        };
+        Ok(PoolOptions {
+            breaker: BreakerOptions {
+                failures: self.breaker_failures,
+                cooldown: Duration::from_secs(self.breaker_cooldown),
+            },
+            limits: self.rpc_limits.clone(),
+            retry,
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ScanArgs {
+    /// token contract address, optionally labelled as `label=0x...`, repeat for more contracts
+    #[arg(short = 't', long = "token", required = true)]
+    pub tokens: Vec<TokenSpec>,
+    #[command(flatten)]
+    pub window: Window,
+    /// initial blocks per eth_getLogs request, adjusted between --min-step and --max-step
+    #[arg(short, long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+    pub step: u64,
+    /// smallest range the step can shrink to on provider limit errors
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub min_step: u64,
+    /// largest range the step can widen to when results are sparse
+    #[arg(long, default_value_t = 5000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_step: u64,
+    /// attempts per block range before it is recorded as failed
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_attempts: u32,
+    /// worker count, default is 4 workers per rpc endpoint
+    #[arg(short, long)]
+    pub workers: Option<NonZeroUsize>,
+    #[command(flatten)]
+    pub pool: PoolArgs,
+    /// sqlite output file
+    #[arg(short, long, default_value = "txs.db")]
+    pub output: PathBuf,
+    /// logs less than this many blocks below the head are stored as unconfirmed
+    #[arg(short, long, default_value_t = 12)]
+    pub confirmations: u64,
+    /// decode all Erc20Token events into per-event tables, not just TokenTransfer
+    #[arg(long)]
+    pub all_events: bool,
+    /// export the transactions next to --output after the scan
+    #[arg(long, value_enum)]
+    pub export: Option<ExportFormat>,
+    /// seconds between progress lines when stderr is not a terminal
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub progress_interval: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct FollowArgs {
+    /// token contract address, optionally labelled as `label=0x...`, repeat for more contracts
+    #[arg(short = 't', long = "token", required = true)]
+    pub tokens: Vec<TokenSpec>,
+    /// sqlite output file
+    #[arg(short, long, default_value = "txs.db")]
+    pub output: PathBuf,
+    /// block to start from when the output has no checkpoint of every token, default is the head
+    #[arg(long)]
+    pub start_block: Option<u64>,
+    /// logs less than this many blocks below the head are unconfirmed and checked for reorgs
+    #[arg(short, long, default_value_t = 12)]
+    pub confirmations: u64,
+    /// seconds between polls for new blocks
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..))]
+    pub poll_interval: u64,
+    /// max blocks per eth_getLogs request
+    #[arg(short, long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub step: u64,
+    #[command(flatten)]
+    pub pool: PoolArgs,
+    /// decode all Erc20Token events into per-event tables, not just TokenTransfer
+    #[arg(long)]
+    pub all_events: bool,
+}
+
+impl FollowArgs {
+    pub fn event_set(&self) -> EventSet {
+        EventSet::from_flag(self.all_events)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ReconcileArgs {
+    #[command(flatten)]
+    pub window: Window,
+    /// sqlite file written by scan
+    #[arg(long, default_value = "txs.db")]
+    pub db: PathBuf,
+    /// json report file, default is stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// sqlite file written by scan
+    #[arg(long, default_value = "txs.db")]
+    pub db: PathBuf,
+    #[arg(short, long, value_enum)]
+    pub format: ExportFormat,
+    /// output file, default is --db with the extension of the format
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+impl Cli {
+    pub fn parse_and_validate() -> Self {
+        let cli = Cli::parse();
+        match &cli.command {
+            Command::Export(_) => {}
+            Command::Follow(args) => check_tokens(&args.tokens),
+            Command::Reconcile(args) => check_window(&args.window),
+            Command::Scan(args) => {
+                check_tokens(&args.tokens);
+                if args.min_step > args.max_step {
+                    Cli::command()
+                        .error(
+                            ErrorKind::ValueValidation,
+                            format!(
+                                "--min-step {} is larger than --max-step {}",
+                                args.min_step, args.max_step
+                            ),
+                        )
+                        .exit();
+                }
+                check_window(&args.window);
+            }
+        }
+        cli
+    }
+}
+
+fn check_window(window: &Window) {
+    if let (Some(from), Some(to)) = (window.from, window.to) {
+        if from > to {
+            Cli::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!("--from {from} is later than --to {to}"),
+                )
+                .exit();
+        }
+    }
+}
+
+fn check_tokens(tokens: &[TokenSpec]) {
+    let mut seen = HashSet::new();
+    for token in tokens {
+        if !seen.insert(token.address) {
+            Cli::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!("--token {:?} is given more than once", token.address),
+                )
+                .exit();
+        }
+    }
+}
+
+impl Window {
+    /// first and last local day, both inclusive
+    pub fn days(&self) -> (NaiveDate, NaiveDate) {
+        match self.date {
+            Some(date) => (date, date),
+            None => (self.from.unwrap(), self.to.unwrap()),
+        }
+    }
+
+    /// start of the first day and end of the last day in the configured timezone
+    pub fn time_window(&self) -> (DateTime<Tz>, DateTime<Tz>) {
+        let (first, last) = self.days();
+        (
+            start_of_day(self.tz, first),
+            start_of_day(self.tz, last + chrono::Duration::days(1)),
+        )
+    }
+
+    /// `time_window` widened by the margins, the blocks to scan
+    pub fn block_window(&self) -> (DateTime<Tz>, DateTime<Tz>) {
+        let (start, end) = self.time_window();
+        (
+            start - chrono::Duration::minutes(self.margin_before.into()),
+            end + chrono::Duration::minutes(self.margin_after.into()),
+        )
+    }
+}
+
+/// local midnight of `date`, or the first instant after it when a DST change at 00:00 skips midnight
+fn start_of_day(tz: Tz, date: NaiveDate) -> DateTime<Tz> {
+    let mut time = date.and_time(NaiveTime::MIN);
+    loop {
+        if let Some(start) = tz.from_local_datetime(&time).earliest() {
+            return start;
+        }
+        time += chrono::Duration::minutes(1);
+    }
+}
+
+impl ScanArgs {
+    pub fn event_set(&self) -> EventSet {
+        EventSet::from_flag(self.all_events)
+    }
+
+    pub fn worker_count(&self, rpc_count: usize) -> usize {
+        self.workers.map_or(rpc_count * 4, NonZeroUsize::get)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn midnight_skipped_by_dst() {
+        // Chile moved clocks from 00:00 to 01:00 on 2022-09-11
+        let tz: Tz = "America/Santiago".parse().unwrap();
+        let start = start_of_day(tz, NaiveDate::from_ymd_opt(2022, 9, 11).unwrap());
+        assert_eq!(
+            start.naive_local().time(),
+            NaiveTime::from_hms_opt(1, 0, 0).unwrap()
+        );
+        assert_eq!(start.to_rfc3339(), "2022-09-11T01:00:00-03:00");
+    }
+
+    #[test]
+    fn ordinary_midnight() {
+        let tz: Tz = "Asia/Tokyo".parse().unwrap();
+        let start = start_of_day(tz, NaiveDate::from_ymd_opt(2023, 11, 28).unwrap());
+        assert_eq!(start.to_rfc3339(), "2023-11-28T00:00:00+09:00");
+    }
+}
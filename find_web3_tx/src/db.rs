@@ -0,0 +1,534 @@
+use std::path::Path;
+
+use chrono::Local;
+use ethers::prelude::Address;
+use futures_util::TryStreamExt;
+use log::warn;
+use sqlx::prelude::*;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+use sqlx::SqliteConnection;
+
+use common::metrics;
+
+use crate::schema::{EventSet, Msg, RangeResult};
+
+/// 按区块存日志的表, 回滚和确认时一起处理
+const LOG_TABLES: [&str; 4] = ["transactions", "transfers", "approvals", "malformed_logs"];
+
+/// 扫描结果的sqlite存储, 每个区块范围的日志和它的checkpoint在同一个事务里提交
+pub struct Store {
+    db: SqliteConnection,
+    events: EventSet,
+    /// 本次扫描的合约地址, 每个合约各自记录checkpoint
+    tokens: Vec<String>,
+}
+
+impl Store {
+    pub async fn open(path: &Path) -> anyhow::Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal);
+        let mut db = SqliteConnection::connect_with(&options).await?;
+
+        sqlx::query("CREATE TABLE IF NOT EXISTS transactions(id INTEGER PRIMARY KEY AUTOINCREMENT,tag_id text NOT NULL,hash text NOT NULL,log_index integer,block integer NOT NULL,block_hash text,log_time text,to_addr text,value text,message text,confirmed integer NOT NULL DEFAULT 1,contract text)").execute(&mut db).await?;
+        // 旧版本建的表缺少后来加的列, 旧数据这些列是NULL, log_index为NULL的行不参与去重
+        add_missing_columns(
+            &mut db,
+            "transactions",
+            &[
+                ("log_index", "integer"),
+                ("block_hash", "text"),
+                ("to_addr", "text"),
+                ("value", "text"),
+                ("message", "text"),
+                ("confirmed", "integer NOT NULL DEFAULT 1"),
+                ("contract", "text"),
+            ],
+        )
+        .await?;
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS transactions_hash_log_index ON transactions(hash,log_index)").execute(&mut db).await?;
+        sqlx::query("CREATE TABLE IF NOT EXISTS transfers(id INTEGER PRIMARY KEY AUTOINCREMENT,hash text NOT NULL,log_index integer NOT NULL,block integer NOT NULL,block_hash text NOT NULL,from_addr text NOT NULL,to_addr text NOT NULL,value text NOT NULL,confirmed integer NOT NULL DEFAULT 1,contract text,UNIQUE(hash,log_index))").execute(&mut db).await?;
+        sqlx::query("CREATE TABLE IF NOT EXISTS approvals(id INTEGER PRIMARY KEY AUTOINCREMENT,hash text NOT NULL,log_index integer NOT NULL,block integer NOT NULL,block_hash text NOT NULL,owner text NOT NULL,spender text NOT NULL,value text NOT NULL,confirmed integer NOT NULL DEFAULT 1,contract text,UNIQUE(hash,log_index))").execute(&mut db).await?;
+        sqlx::query("CREATE TABLE IF NOT EXISTS malformed_logs(id INTEGER PRIMARY KEY AUTOINCREMENT,hash text NOT NULL,log_index integer NOT NULL,block integer NOT NULL,block_hash text NOT NULL,contract text,message text NOT NULL,error text NOT NULL,confirmed integer NOT NULL DEFAULT 1,UNIQUE(hash,log_index))").execute(&mut db).await?;
+        add_missing_columns(&mut db, "transfers", &[("contract", "text")]).await?;
+        add_missing_columns(&mut db, "approvals", &[("contract", "text")]).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS contracts(address text PRIMARY KEY,label text NOT NULL)",
+        )
+        .execute(&mut db)
+        .await?;
+        sqlx::query("CREATE VIEW IF NOT EXISTS mints AS SELECT * FROM transfers WHERE from_addr='0x0000000000000000000000000000000000000000'").execute(&mut db).await?;
+        sqlx::query("CREATE TABLE IF NOT EXISTS checkpoints(token text NOT NULL,start integer NOT NULL,stop integer NOT NULL,status text NOT NULL,events text NOT NULL DEFAULT 'token_transfer',logs integer NOT NULL,attempts integer NOT NULL DEFAULT 0,error text,stop_hash text,updated_at text NOT NULL,PRIMARY KEY(token,start,stop))").execute(&mut db).await?;
+        add_missing_columns(
+            &mut db,
+            "checkpoints",
+            &[
+                ("stop_hash", "text"),
+                ("events", "text NOT NULL DEFAULT 'token_transfer'"),
+            ],
+        )
+        .await?;
+        Ok(Self {
+            db,
+            events: EventSet::default(),
+            tokens: Vec::new(),
+        })
+    }
+
+    /// 记录合约的label, 后续的checkpoint都按这些合约读写
+    pub async fn with_tokens(mut self, tokens: &[(Address, String)]) -> anyhow::Result<Self> {
+        for (address, label) in tokens {
+            sqlx::query("INSERT INTO contracts(address,label) VALUES(?,?) ON CONFLICT(address) DO UPDATE SET label=excluded.label")
+                .bind(format!("{address:?}"))
+                .bind(label)
+                .execute(&mut self.db)
+                .await?;
+        }
+        self.tokens = tokens
+            .iter()
+            .map(|(address, _)| format!("{address:?}"))
+            .collect();
+        Ok(self)
+    }
+
+    /// 全部事件的checkpoint也满足只查TokenTransfer的扫描, 反过来不行
+    pub fn with_events(mut self, events: EventSet) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// gaps of `[from_block, to_block]` not yet recorded as done for every token
+    pub async fn pending_gaps(
+        &mut self,
+        from_block: u64,
+        to_block: u64,
+    ) -> anyhow::Result<Vec<(u64, u64)>> {
+        let mut gaps = Vec::new();
+        for token in &self.tokens {
+            let done: Vec<(i64, i64)> = sqlx::query_as(
+                "SELECT start,stop FROM checkpoints WHERE token=? AND status='done' AND events IN ('all',?) AND stop>=? AND start<=? ORDER BY start",
+            )
+            .bind(token)
+            .bind(self.events.as_str())
+            .bind(from_block as i64)
+            .bind(to_block as i64)
+            .fetch_all(&mut self.db)
+            .await?;
+            gaps.extend(gaps_between(&done, from_block, to_block));
+        }
+        Ok(merge_ranges(gaps))
+    }
+
+    /// lowest of the highest done blocks of the tokens, `None` if any token has no checkpoint
+    pub async fn last_done_block(&mut self) -> anyhow::Result<Option<u64>> {
+        let mut last: Option<u64> = None;
+        for token in &self.tokens {
+            let (stop,): (Option<i64>,) = sqlx::query_as(
+                "SELECT MAX(stop) FROM checkpoints WHERE token=? AND status='done' AND events IN ('all',?)",
+            )
+            .bind(token)
+            .bind(self.events.as_str())
+            .fetch_one(&mut self.db)
+            .await?;
+            let Some(stop) = stop else {
+                return Ok(None);
+            };
+            last = Some(last.map_or(stop as u64, |last| last.min(stop as u64)));
+        }
+        Ok(last)
+    }
+
+    pub async fn save_range(&mut self, result: RangeResult) -> anyhow::Result<()> {
+        let mut tx = sqlx::Connection::begin(&mut self.db).await?;
+        for row in &result.rows {
+            sqlx::query("INSERT INTO transactions(tag_id,hash,log_index,block,block_hash,log_time,to_addr,value,message,confirmed,contract) VALUES(?,?,?,?,?,?,?,?,?,?,?) ON CONFLICT(hash,log_index) DO UPDATE SET tag_id=excluded.tag_id,contract=excluded.contract,block=excluded.block,block_hash=excluded.block_hash,log_time=excluded.log_time,to_addr=excluded.to_addr,value=excluded.value,message=excluded.message,confirmed=excluded.confirmed")
+                .bind(&row.tag_id)
+                .bind(&row.hash)
+                .bind(row.log_index as i64)
+                .bind(row.block as i64)
+                .bind(&row.block_hash)
+                .bind(&row.log_time)
+                .bind(&row.to_addr)
+                .bind(&row.value)
+                .bind(&row.message)
+                .bind(row.confirmed)
+                .bind(&row.contract)
+                .execute(&mut *tx)
+                .await?;
+        }
+        for row in &result.transfers {
+            sqlx::query("INSERT INTO transfers(hash,log_index,block,block_hash,from_addr,to_addr,value,confirmed,contract) VALUES(?,?,?,?,?,?,?,?,?) ON CONFLICT(hash,log_index) DO UPDATE SET contract=excluded.contract,block=excluded.block,block_hash=excluded.block_hash,from_addr=excluded.from_addr,to_addr=excluded.to_addr,value=excluded.value,confirmed=excluded.confirmed")
+                .bind(&row.hash)
+                .bind(row.log_index as i64)
+                .bind(row.block as i64)
+                .bind(&row.block_hash)
+                .bind(&row.from_addr)
+                .bind(&row.to_addr)
+                .bind(&row.value)
+                .bind(row.confirmed)
+                .bind(&row.contract)
+                .execute(&mut *tx)
+                .await?;
+        }
+        for row in &result.approvals {
+            sqlx::query("INSERT INTO approvals(hash,log_index,block,block_hash,owner,spender,value,confirmed,contract) VALUES(?,?,?,?,?,?,?,?,?) ON CONFLICT(hash,log_index) DO UPDATE SET contract=excluded.contract,block=excluded.block,block_hash=excluded.block_hash,owner=excluded.owner,spender=excluded.spender,value=excluded.value,confirmed=excluded.confirmed")
+                .bind(&row.hash)
+                .bind(row.log_index as i64)
+                .bind(row.block as i64)
+                .bind(&row.block_hash)
+                .bind(&row.owner)
+                .bind(&row.spender)
+                .bind(&row.value)
+                .bind(row.confirmed)
+                .bind(&row.contract)
+                .execute(&mut *tx)
+                .await?;
+        }
+        for row in &result.malformed {
+            sqlx::query("INSERT INTO malformed_logs(hash,log_index,block,block_hash,contract,message,error,confirmed) VALUES(?,?,?,?,?,?,?,?) ON CONFLICT(hash,log_index) DO UPDATE SET contract=excluded.contract,block=excluded.block,block_hash=excluded.block_hash,message=excluded.message,error=excluded.error,confirmed=excluded.confirmed")
+                .bind(&row.hash)
+                .bind(row.log_index as i64)
+                .bind(row.block as i64)
+                .bind(&row.block_hash)
+                .bind(&row.contract)
+                .bind(&row.message)
+                .bind(&row.error)
+                .bind(row.confirmed)
+                .execute(&mut *tx)
+                .await?;
+        }
+        // 已经按全部事件扫过的范围只查TokenTransfer重跑时不降级, 不然下次全部事件还要再扫
+        for token in &self.tokens {
+            sqlx::query("INSERT INTO checkpoints(token,start,stop,status,events,logs,attempts,error,stop_hash,updated_at) VALUES(?,?,?,'done',?,?,?,NULL,?,?) ON CONFLICT(token,start,stop) DO UPDATE SET status=excluded.status,events=CASE WHEN checkpoints.events='all' THEN 'all' ELSE excluded.events END,logs=excluded.logs,attempts=excluded.attempts,error=NULL,stop_hash=excluded.stop_hash,updated_at=excluded.updated_at")
+                .bind(token)
+                .bind(result.range.start as i64)
+                .bind(result.range.stop as i64)
+                .bind(self.events.as_str())
+                .bind(result.logs_of(token) as i64)
+                .bind(result.range.attempt + 1)
+                .bind(&result.stop_hash)
+                .bind(Local::now().to_rfc3339())
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        metrics::range_done(result.logs());
+        for (table, rows) in LOG_TABLES.iter().zip([
+            result.rows.len(),
+            result.transfers.len(),
+            result.approvals.len(),
+            result.malformed.len(),
+        ]) {
+            metrics::rows_inserted(table, rows);
+        }
+        Ok(())
+    }
+
+    pub async fn save_failed(&mut self, range: Msg, error: &str) -> anyhow::Result<()> {
+        for token in &self.tokens {
+            sqlx::query("INSERT INTO checkpoints(token,start,stop,status,events,logs,attempts,error,updated_at) VALUES(?,?,?,'failed',?,0,?,?,?) ON CONFLICT(token,start,stop) DO UPDATE SET status=excluded.status,events=excluded.events,logs=0,attempts=excluded.attempts,error=excluded.error,updated_at=excluded.updated_at")
+                .bind(token)
+                .bind(range.start as i64)
+                .bind(range.stop as i64)
+                .bind(self.events.as_str())
+                .bind(range.attempt + 1)
+                .bind(error)
+                .bind(Local::now().to_rfc3339())
+                .execute(&mut self.db)
+                .await?;
+        }
+        metrics::range_failed();
+        Ok(())
+    }
+
+    /// 删除窗口内旧的失败记录, 这些范围本次会重新扫描
+    pub async fn clear_failed(&mut self, from_block: u64, to_block: u64) -> anyhow::Result<()> {
+        for token in &self.tokens {
+            sqlx::query(
+                "DELETE FROM checkpoints WHERE token=? AND status='failed' AND stop>=? AND start<=?",
+            )
+            .bind(token)
+            .bind(from_block as i64)
+            .bind(to_block as i64)
+            .execute(&mut self.db)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// 所有合约共用一次请求, 同一个失败范围只列一次
+    pub async fn failed_ranges(
+        &mut self,
+        from_block: u64,
+        to_block: u64,
+    ) -> anyhow::Result<Vec<(u64, u64, String)>> {
+        let mut failed = Vec::new();
+        for token in &self.tokens {
+            let rows: Vec<(i64, i64, Option<String>)> = sqlx::query_as(
+                "SELECT start,stop,error FROM checkpoints WHERE token=? AND status='failed' AND stop>=? AND start<=? ORDER BY start",
+            )
+            .bind(token)
+            .bind(from_block as i64)
+            .bind(to_block as i64)
+            .fetch_all(&mut self.db)
+            .await?;
+            failed.extend(rows.into_iter().map(|(start, stop, error)| {
+                (start as u64, stop as u64, error.unwrap_or_default())
+            }));
+        }
+        failed.sort();
+        failed.dedup_by_key(|(start, stop, _)| (*start, *stop));
+        Ok(failed)
+    }
+
+    /// done ranges ending above `safe_block` that carry the hash of their stop block, newest first
+    pub async fn unconfirmed_checkpoints(
+        &mut self,
+        safe_block: u64,
+    ) -> anyhow::Result<Vec<(u64, u64, String)>> {
+        let mut ranges = Vec::new();
+        for token in &self.tokens {
+            let rows: Vec<(i64, i64, String)> = sqlx::query_as(
+                "SELECT start,stop,stop_hash FROM checkpoints WHERE token=? AND status='done' AND stop>? AND stop_hash IS NOT NULL",
+            )
+            .bind(token)
+            .bind(safe_block as i64)
+            .fetch_all(&mut self.db)
+            .await?;
+            ranges.extend(
+                rows.into_iter()
+                    .map(|(start, stop, hash)| (start as u64, stop as u64, hash)),
+            );
+        }
+        ranges.sort_by_key(|(start, stop, _)| std::cmp::Reverse((*stop, *start)));
+        ranges.dedup_by_key(|(start, stop, _)| (*start, *stop));
+        Ok(ranges)
+    }
+
+    /// 分叉后删除`from_block`及之后的checkpoint和日志
+    pub async fn rollback(&mut self, from_block: u64) -> anyhow::Result<u64> {
+        let mut tx = sqlx::Connection::begin(&mut self.db).await?;
+        let mut deleted = 0;
+        for token in &self.tokens {
+            sqlx::query("DELETE FROM checkpoints WHERE token=? AND stop>=?")
+                .bind(token)
+                .bind(from_block as i64)
+                .execute(&mut *tx)
+                .await?;
+            for table in LOG_TABLES {
+                deleted += sqlx::query(&format!(
+                    "DELETE FROM {table} WHERE block>=? AND (contract=? OR contract IS NULL)"
+                ))
+                .bind(from_block as i64)
+                .bind(token)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            }
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    /// mark logs at or below `safe_block` as confirmed
+    pub async fn confirm(&mut self, safe_block: u64) -> anyhow::Result<u64> {
+        let mut updated = 0;
+        for table in LOG_TABLES {
+            updated += sqlx::query(&format!(
+                "UPDATE {table} SET confirmed=1 WHERE confirmed=0 AND block<=?"
+            ))
+            .bind(safe_block as i64)
+            .execute(&mut self.db)
+            .await?
+            .rows_affected();
+        }
+        Ok(updated)
+    }
+
+    pub fn connection(&mut self) -> &mut SqliteConnection {
+        &mut self.db
+    }
+
+    pub async fn report(&mut self) -> anyhow::Result<()> {
+        let count: (i32, i32) =
+            sqlx::query_as("SELECT COUNT(tag_id),COUNT(DISTINCT tag_id) FROM transactions")
+                .fetch_one(&mut self.db)
+                .await?;
+        warn!("total tx_hash {}, distinct tx_hash {}", count.0, count.1);
+        let mut rows = sqlx::query(
+            "SELECT tag_id, COUNT(*) AS quantity FROM transactions GROUP BY tag_id HAVING quantity>1 limit 5",
+        ).fetch(&mut self.db);
+        while let Some(row) = rows.try_next().await? {
+            warn!(
+                "{},{}",
+                row.get::<String, _>("tag_id"),
+                row.get::<i32, _>("quantity")
+            )
+        }
+        drop(rows);
+        let (malformed,): (i32,) = sqlx::query_as("SELECT COUNT(*) FROM malformed_logs")
+            .fetch_one(&mut self.db)
+            .await?;
+        if malformed > 0 {
+            warn!("{malformed} TokenTransfer logs with malformed message, see malformed_logs");
+        }
+        Ok(())
+    }
+}
+
+async fn add_missing_columns(
+    db: &mut SqliteConnection,
+    table: &str,
+    columns: &[(&str, &str)],
+) -> anyhow::Result<()> {
+    let existing: Vec<(String,)> = sqlx::query_as("SELECT name FROM pragma_table_info(?)")
+        .bind(table)
+        .fetch_all(&mut *db)
+        .await?;
+    for (column, ty) in columns {
+        if !existing.iter().any(|(name,)| name == column) {
+            sqlx::query(&format!("ALTER TABLE {table} ADD COLUMN {column} {ty}"))
+                .execute(&mut *db)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// `[from_block, to_block]`里没被`done`覆盖的部分, `done`按start排序
+pub fn gaps_between(done: &[(i64, i64)], from_block: u64, to_block: u64) -> Vec<(u64, u64)> {
+    let mut gaps = Vec::new();
+    let mut cursor = from_block;
+    for &(start, stop) in done {
+        let (start, stop) = (start as u64, stop as u64);
+        if start > cursor {
+            gaps.push((cursor, start - 1));
+        }
+        cursor = cursor.max(stop + 1);
+    }
+    if cursor <= to_block {
+        gaps.push((cursor, to_block));
+    }
+    gaps
+}
+
+/// 合并重叠或相邻的区间
+pub fn merge_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, stop) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(stop),
+            _ => merged.push((start, stop)),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn store(tokens: u64, events: EventSet) -> Store {
+        let tokens: Vec<_> = (1..=tokens)
+            .map(|i| (Address::from_low_u64_be(i), format!("t{i}")))
+            .collect();
+        Store::open(Path::new(":memory:"))
+            .await
+            .unwrap()
+            .with_tokens(&tokens)
+            .await
+            .unwrap()
+            .with_events(events)
+    }
+
+    fn token(i: u64) -> String {
+        format!("{:?}", Address::from_low_u64_be(i))
+    }
+
+    async fn done(store: &mut Store, token: &str, start: u64, stop: u64, events: EventSet) {
+        sqlx::query("INSERT INTO checkpoints(token,start,stop,status,events,logs,updated_at) VALUES(?,?,?,'done',?,0,'')")
+            .bind(token)
+            .bind(start as i64)
+            .bind(stop as i64)
+            .bind(events.as_str())
+            .execute(store.connection())
+            .await
+            .unwrap();
+    }
+
+    fn range(start: u64, stop: u64) -> RangeResult {
+        RangeResult {
+            range: Msg::new(start, stop),
+            rows: Vec::new(),
+            transfers: Vec::new(),
+            approvals: Vec::new(),
+            malformed: Vec::new(),
+            stop_hash: None,
+        }
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent() {
+        assert_eq!(
+            merge_ranges(vec![(20, 30), (0, 5), (6, 9), (25, 26), (11, 12)]),
+            vec![(0, 9), (11, 12), (20, 30)]
+        );
+        assert_eq!(merge_ranges(Vec::new()), Vec::new());
+    }
+
+    #[tokio::test]
+    async fn gaps_around_overlapping_checkpoints() {
+        let mut store = store(1, EventSet::TokenTransfer).await;
+        let t = token(1);
+        for (start, stop) in [(10, 20), (15, 30), (31, 40), (60, 70)] {
+            done(&mut store, &t, start, stop, EventSet::TokenTransfer).await;
+        }
+        assert_eq!(
+            store.pending_gaps(0, 65).await.unwrap(),
+            vec![(0, 9), (41, 59)]
+        );
+        assert_eq!(store.pending_gaps(12, 35).await.unwrap(), Vec::new());
+        assert_eq!(store.pending_gaps(71, 80).await.unwrap(), vec![(71, 80)]);
+    }
+
+    #[tokio::test]
+    async fn gaps_are_the_union_over_tokens() {
+        let mut store = store(2, EventSet::TokenTransfer).await;
+        done(&mut store, &token(1), 0, 50, EventSet::TokenTransfer).await;
+        done(&mut store, &token(2), 0, 20, EventSet::TokenTransfer).await;
+        done(&mut store, &token(2), 30, 50, EventSet::TokenTransfer).await;
+        assert_eq!(store.pending_gaps(0, 50).await.unwrap(), vec![(21, 29)]);
+
+        done(&mut store, &token(1), 60, 70, EventSet::TokenTransfer).await;
+        assert_eq!(
+            store.pending_gaps(0, 70).await.unwrap(),
+            vec![(21, 29), (51, 70)]
+        );
+    }
+
+    #[tokio::test]
+    async fn all_events_checkpoint_covers_token_transfer_only() {
+        let mut store = store(1, EventSet::TokenTransfer).await;
+        done(&mut store, &token(1), 0, 9, EventSet::All).await;
+        done(&mut store, &token(1), 10, 19, EventSet::TokenTransfer).await;
+        assert_eq!(store.pending_gaps(0, 19).await.unwrap(), Vec::new());
+
+        let mut store = store.with_events(EventSet::All);
+        assert_eq!(store.pending_gaps(0, 19).await.unwrap(), vec![(10, 19)]);
+    }
+
+    #[tokio::test]
+    async fn rerun_does_not_downgrade_all_events() {
+        let mut store = store(1, EventSet::All).await;
+        store.save_range(range(0, 9)).await.unwrap();
+        let mut store = store.with_events(EventSet::TokenTransfer);
+        store.save_range(range(0, 9)).await.unwrap();
+        store.save_range(range(10, 19)).await.unwrap();
+
+        let mut store = store.with_events(EventSet::All);
+        assert_eq!(store.pending_gaps(0, 19).await.unwrap(), vec![(10, 19)]);
+    }
+}
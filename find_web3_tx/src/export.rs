@@ -0,0 +1,268 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use clap::ValueEnum;
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{Connection, SqliteConnection};
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// transactions表的一行, 包括解码出来的TokenTransfer字段
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ExportRow {
+    pub id: i64,
+    pub tag_id: String,
+    pub hash: String,
+    pub log_index: Option<i64>,
+    pub block: i64,
+    pub block_hash: Option<String>,
+    pub log_time: Option<String>,
+    pub to_addr: Option<String>,
+    pub value: Option<String>,
+    pub message: Option<String>,
+    pub confirmed: bool,
+    pub contract: Option<String>,
+    /// contracts表里的label
+    pub label: Option<String>,
+}
+
+trait RowSink: Send {
+    fn write(&mut self, row: ExportRow) -> anyhow::Result<()>;
+    fn finish(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+struct CsvSink(csv::Writer<BufWriter<File>>);
+
+impl RowSink for CsvSink {
+    fn write(&mut self, row: ExportRow) -> anyhow::Result<()> {
+        self.0.serialize(row)?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+struct JsonlSink(BufWriter<File>);
+
+impl RowSink for JsonlSink {
+    fn write(&mut self, row: ExportRow) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.0, &row)?;
+        self.0.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_sink {
+    use std::fs::File;
+    use std::sync::Arc;
+
+    use arrow_array::{ArrayRef, BooleanArray, Int64Array, RecordBatch, StringArray};
+    use arrow_schema::{DataType, Field, Schema, SchemaRef};
+    use parquet::arrow::ArrowWriter;
+
+    use super::{ExportRow, RowSink};
+
+    const BATCH_SIZE: usize = 8192;
+
+    pub struct ParquetSink {
+        writer: ArrowWriter<File>,
+        schema: SchemaRef,
+        rows: Vec<ExportRow>,
+    }
+
+    impl ParquetSink {
+        pub fn new(file: File) -> anyhow::Result<Self> {
+            let schema = Arc::new(Schema::new(vec![
+                Field::new("id", DataType::Int64, false),
+                Field::new("tag_id", DataType::Utf8, false),
+                Field::new("hash", DataType::Utf8, false),
+                Field::new("log_index", DataType::Int64, true),
+                Field::new("block", DataType::Int64, false),
+                Field::new("block_hash", DataType::Utf8, true),
+                Field::new("log_time", DataType::Utf8, true),
+                Field::new("to_addr", DataType::Utf8, true),
+                Field::new("value", DataType::Utf8, true),
+                Field::new("message", DataType::Utf8, true),
+                Field::new("confirmed", DataType::Boolean, false),
+                Field::new("contract", DataType::Utf8, true),
+                Field::new("label", DataType::Utf8, true),
+            ]));
+            let writer = ArrowWriter::try_new(file, schema.clone(), None)?;
+            Ok(Self {
+                writer,
+                schema,
+                rows: Vec::with_capacity(BATCH_SIZE),
+            })
+        }
+
+        fn flush_batch(&mut self) -> anyhow::Result<()> {
+            if self.rows.is_empty() {
+                return Ok(());
+            }
+            let rows = std::mem::take(&mut self.rows);
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(Int64Array::from_iter_values(rows.iter().map(|r| r.id))),
+                Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|r| &r.tag_id),
+                )),
+                Arc::new(StringArray::from_iter_values(rows.iter().map(|r| &r.hash))),
+                Arc::new(Int64Array::from_iter(rows.iter().map(|r| r.log_index))),
+                Arc::new(Int64Array::from_iter_values(rows.iter().map(|r| r.block))),
+                Arc::new(StringArray::from_iter(
+                    rows.iter().map(|r| r.block_hash.as_deref()),
+                )),
+                Arc::new(StringArray::from_iter(
+                    rows.iter().map(|r| r.log_time.as_deref()),
+                )),
+                Arc::new(StringArray::from_iter(
+                    rows.iter().map(|r| r.to_addr.as_deref()),
+                )),
+                Arc::new(StringArray::from_iter(
+                    rows.iter().map(|r| r.value.as_deref()),
+                )),
+                Arc::new(StringArray::from_iter(
+                    rows.iter().map(|r| r.message.as_deref()),
+                )),
+                Arc::new(BooleanArray::from_iter(
+                    rows.iter().map(|r| Some(r.confirmed)),
+                )),
+                Arc::new(StringArray::from_iter(
+                    rows.iter().map(|r| r.contract.as_deref()),
+                )),
+                Arc::new(StringArray::from_iter(
+                    rows.iter().map(|r| r.label.as_deref()),
+                )),
+            ];
+            let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+            self.writer.write(&batch)?;
+            Ok(())
+        }
+    }
+
+    impl RowSink for ParquetSink {
+        fn write(&mut self, row: ExportRow) -> anyhow::Result<()> {
+            self.rows.push(row);
+            if self.rows.len() >= BATCH_SIZE {
+                self.flush_batch()?;
+            }
+            Ok(())
+        }
+
+        fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+            self.flush_batch()?;
+            self.writer.close()?;
+            Ok(())
+        }
+    }
+}
+
+/// 只读打开scan写的文件, 不存在时报错, 也不升级旧版本的表结构
+pub async fn open_db(path: &Path) -> anyhow::Result<SqliteConnection> {
+    anyhow::ensure!(path.is_file(), "{} does not exist", path.display());
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    Ok(SqliteConnection::connect_with(&options).await?)
+}
+
+/// 表的列名, 表不存在时为空
+pub async fn table_columns(db: &mut SqliteConnection, table: &str) -> anyhow::Result<Vec<String>> {
+    Ok(sqlx::query_scalar("SELECT name FROM pragma_table_info(?)")
+        .bind(table)
+        .fetch_all(db)
+        .await?)
+}
+
+/// 把transactions表写到文件, 返回写出的行数
+pub async fn export(
+    db: &mut SqliteConnection,
+    format: ExportFormat,
+    path: &Path,
+) -> anyhow::Result<usize> {
+    let query = export_query(db).await?;
+    let mut sink: Box<dyn RowSink> = match format {
+        ExportFormat::Csv => Box::new(CsvSink(csv::Writer::from_writer(BufWriter::new(
+            File::create(path)?,
+        )))),
+        ExportFormat::Jsonl => Box::new(JsonlSink(BufWriter::new(File::create(path)?))),
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => Box::new(parquet_sink::ParquetSink::new(File::create(path)?)?),
+    };
+    let mut rows = sqlx::query_as::<_, ExportRow>(&query).fetch(db);
+    let mut count = 0;
+    while let Some(row) = rows.try_next().await? {
+        sink.write(row)?;
+        count += 1;
+    }
+    sink.finish()?;
+    Ok(count)
+}
+
+/// 旧版本的文件缺少后来加的列和contracts表, 缺的列导出为NULL
+async fn export_query(db: &mut SqliteConnection) -> anyhow::Result<String> {
+    let columns = table_columns(db, "transactions").await?;
+    anyhow::ensure!(!columns.is_empty(), "no transactions table in the database");
+    let has_contracts: bool = sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type='table' AND name='contracts')",
+    )
+    .fetch_one(&mut *db)
+    .await?;
+    let has = |name: &str| columns.iter().any(|c| c == name);
+    let column = |name: &str, missing: &str| {
+        if has(name) {
+            format!("t.{name}")
+        } else {
+            format!("{missing} AS {name}")
+        }
+    };
+    let selected = [
+        column("log_index", "NULL"),
+        column("block_hash", "NULL"),
+        column("to_addr", "NULL"),
+        column("value", "NULL"),
+        column("message", "NULL"),
+        column("confirmed", "1"),
+        column("contract", "NULL"),
+    ]
+    .join(",");
+    let (label, join) = if has_contracts && has("contract") {
+        ("c.label", "LEFT JOIN contracts c ON c.address=t.contract")
+    } else {
+        ("NULL AS label", "")
+    };
+    let order = if has("log_index") {
+        "t.block,t.log_index"
+    } else {
+        "t.block,t.id"
+    };
+    Ok(format!(
+        "SELECT t.id,t.tag_id,t.hash,t.block,t.log_time,{selected},{label} FROM transactions t {join} ORDER BY {order}"
+    ))
+}
@@ -0,0 +1,133 @@
+use std::cmp::min;
+use std::time::Duration;
+
+use ethers::prelude::*;
+use log::{info, warn};
+
+use common::erc20::*;
+use common::metrics;
+use common::shutdown::Shutdown;
+
+use crate::cli::{token_pairs, FollowArgs};
+use crate::db::Store;
+use crate::pool::{PoolProvider, ProviderPool};
+use crate::schema::Msg;
+use crate::utils::{is_range_limit_error, AdaptiveStep};
+use crate::{fetch_range, SETTING};
+
+/// 从上次的checkpoint开始轮询新区块, 一直索引到链头, 离链头不到确认数的日志标记为未确认,
+/// 每轮先检查未确认范围的结束区块hash, 发现分叉就回滚到分叉点重新扫描
+pub async fn follow(args: FollowArgs) -> anyhow::Result<()> {
+    let contracts: Vec<Address> = args.tokens.iter().map(|t| t.address).collect();
+    let poll_interval = Duration::from_secs(args.poll_interval);
+    if let Some(addr) = &SETTING.metrics_addr {
+        metrics::serve(addr).await?;
+    }
+    let w3 = ProviderPool::provider(&SETTING.rpc_list, args.pool.options()?)?;
+    let c = Erc20Token::new(contracts[0], w3.clone());
+    let events = args.event_set();
+    let mut store = Store::open(&args.output)
+        .await?
+        .with_events(events)
+        .with_tokens(&token_pairs(&args.tokens))
+        .await?;
+    let step = AdaptiveStep::new(args.step, 1, args.step);
+
+    let mut next = match store.last_done_block().await? {
+        Some(block) => block + 1,
+        None => match args.start_block {
+            Some(block) => block,
+            None => w3.get_block_number().await?.as_u64(),
+        },
+    };
+    info!(
+        "follow {} tokens from block {next}, confirmations={}",
+        contracts.len(),
+        args.confirmations
+    );
+
+    // 收到信号时做完当前范围再退出, 已保存的checkpoint下次启动接着用
+    let mut shutdown = Shutdown::listen();
+    while !shutdown.is_triggered() {
+        let head = match w3.get_block_number().await {
+            Ok(head) => head.as_u64(),
+            Err(e) => {
+                warn!("get head error: {e}");
+                shutdown.sleep(poll_interval).await;
+                continue;
+            }
+        };
+        let safe = head.saturating_sub(args.confirmations);
+        match find_fork(&w3, &mut store, safe).await {
+            Ok(Some(fork)) => {
+                let deleted = store.rollback(fork).await?;
+                warn!("reorg detected, rollback from block {fork}, {deleted} logs removed");
+                next = fork;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("reorg check error: {e:#}");
+                shutdown.sleep(poll_interval).await;
+                continue;
+            }
+        }
+        store.confirm(safe).await?;
+
+        while next <= head && !shutdown.is_triggered() {
+            let msg = Msg::new(next, min(next + step.get() - 1, head));
+            match fetch_range(&c, &contracts, msg, safe, events).await {
+                Ok(result) => {
+                    step.observe(msg.blocks(), result.logs());
+                    if result.logs() > 0 {
+                        info!(
+                            "indexed {} logs in blocks {}..={}",
+                            result.logs(),
+                            msg.start,
+                            msg.stop
+                        );
+                    }
+                    store.save_range(result).await?;
+                    next = msg.stop + 1;
+                }
+                Err(e) if msg.blocks() > 1 && is_range_limit_error(&format!("{e:#}")) => {
+                    let new_step = step.shrink(msg.blocks());
+                    warn!(
+                        "range limit, start={}, stop={}, step={new_step}",
+                        msg.start, msg.stop
+                    );
+                }
+                Err(e) => {
+                    warn!(
+                        "fetch error: {e:#}, start={}, stop={}, retry later",
+                        msg.start, msg.stop
+                    );
+                    break;
+                }
+            }
+        }
+        shutdown.sleep(poll_interval).await;
+    }
+    info!("follow stopped, next block {next}");
+    store.report().await
+}
+
+/// 从最新的未确认范围往回比较结束区块的hash, 返回最早一个对不上的范围的起点;
+/// 节点没有返回区块时不算分叉, 返回错误等下一轮再查
+async fn find_fork(
+    w3: &PoolProvider,
+    store: &mut Store,
+    safe_block: u64,
+) -> anyhow::Result<Option<u64>> {
+    let mut fork = None;
+    for (start, stop, stored_hash) in store.unconfirmed_checkpoints(safe_block).await? {
+        // 节点池里落后的节点还没有这个区块, 当成分叉会误删已经确认的数据
+        let Some(hash) = w3.get_block(stop).await?.and_then(|block| block.hash) else {
+            anyhow::bail!("block {stop} not found, retry later");
+        };
+        if format!("{hash:?}") == stored_hash {
+            break;
+        }
+        fork = Some(start);
+    }
+    Ok(fork)
+}
@@ -0,0 +1,317 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDate, Utc};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// 一个rpc节点的配额, 对应`--rpc-limit`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimit {
+    /// 每秒请求数
+    pub per_second: Option<f64>,
+    /// 令牌桶容量, 默认等于每秒请求数
+    pub burst: Option<u32>,
+    /// 同时进行的请求数
+    pub concurrency: Option<usize>,
+    /// 每天(UTC)的请求数
+    pub daily: Option<u64>,
+}
+
+/// `PATTERN=RATE[,burst=N][,concurrency=N][,daily=N]`, PATTERN是rpc url的子串, `*`匹配所有节点
+#[derive(Debug, Clone)]
+pub struct RpcLimit {
+    pub pattern: String,
+    pub limit: RateLimit,
+}
+
+impl RpcLimit {
+    pub fn matches(&self, url: &str) -> bool {
+        self.pattern == "*" || url.contains(&self.pattern)
+    }
+
+    /// 最后一个匹配的配置生效, 和命令行里的顺序一致
+    pub fn find<'a>(limits: &'a [RpcLimit], url: &str) -> Option<&'a RateLimit> {
+        limits
+            .iter()
+            .rev()
+            .find(|l| l.matches(url))
+            .map(|l| &l.limit)
+    }
+}
+
+impl FromStr for RpcLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, spec) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected PATTERN=RATE[,key=value...], got {s:?}"))?;
+        if pattern.is_empty() {
+            return Err(format!("empty url pattern in {s:?}"));
+        }
+        let mut limit = RateLimit::default();
+        for (i, part) in spec.split(',').map(str::trim).enumerate() {
+            let (key, value) = match part.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None if i == 0 && !part.is_empty() => ("rate", part),
+                None => return Err(format!("invalid limit {part:?} in {s:?}")),
+            };
+            let invalid = |e: &dyn std::fmt::Display| format!("invalid {key} {value:?}: {e}");
+            match key {
+                "rate" => {
+                    let rate: f64 = value.parse().map_err(|e| invalid(&e))?;
+                    if rate <= 0.0 || !rate.is_finite() {
+                        return Err(invalid(&"must be positive"));
+                    }
+                    limit.per_second = Some(rate);
+                }
+                "burst" => limit.burst = Some(positive(value).map_err(|e| invalid(&e))?),
+                "concurrency" => {
+                    let concurrency = positive(value).map_err(|e| invalid(&e))?;
+                    if concurrency > Semaphore::MAX_PERMITS {
+                        return Err(invalid(&format!(
+                            "must be at most {}",
+                            Semaphore::MAX_PERMITS
+                        )));
+                    }
+                    limit.concurrency = Some(concurrency);
+                }
+                "daily" => limit.daily = Some(positive(value).map_err(|e| invalid(&e))?),
+                _ => return Err(format!("unknown limit {key:?} in {s:?}")),
+            }
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            limit,
+        })
+    }
+}
+
+fn positive<T>(value: &str) -> Result<T, String>
+where
+    T: FromStr + Default + PartialEq,
+    T::Err: std::fmt::Display,
+{
+    match value.parse::<T>() {
+        Ok(n) if n == T::default() => Err("must be positive".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// 按固定速率补充的令牌桶
+#[derive(Debug)]
+struct TokenBucket {
+    per_second: f64,
+    capacity: f64,
+    /// (剩余令牌, 上次补充的时间)
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(per_second: f64, burst: Option<u32>) -> Self {
+        let capacity = burst.map_or(per_second.max(1.0), f64::from);
+        Self {
+            per_second,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                let (tokens, last) = *state;
+                let tokens = (tokens + now.duration_since(last).as_secs_f64() * self.per_second)
+                    .min(self.capacity);
+                if tokens >= 1.0 {
+                    *state = (tokens - 1.0, now);
+                    return;
+                }
+                *state = (tokens, now);
+                Duration::from_secs_f64((1.0 - tokens) / self.per_second)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// 计数只在内存里, 重启后从0开始
+#[derive(Debug)]
+struct DailyQuota {
+    limit: u64,
+    used: AtomicU64,
+    day: Mutex<NaiveDate>,
+}
+
+impl DailyQuota {
+    fn reset_if_new_day(&self) {
+        let today = Utc::now().date_naive();
+        let mut day = self.day.lock().unwrap();
+        if *day != today {
+            *day = today;
+            self.used.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 一个节点的限流器, 每次http请求之前调用, 包括重试
+#[derive(Debug, Default)]
+pub struct Limiter {
+    bucket: Option<TokenBucket>,
+    concurrency: Option<Semaphore>,
+    daily: Option<DailyQuota>,
+}
+
+impl Limiter {
+    pub fn new(limit: Option<&RateLimit>) -> Self {
+        let Some(limit) = limit else {
+            return Self::default();
+        };
+        Self {
+            bucket: limit
+                .per_second
+                .map(|per_second| TokenBucket::new(per_second, limit.burst)),
+            concurrency: limit.concurrency.map(Semaphore::new),
+            daily: limit.daily.map(|limit| DailyQuota {
+                limit,
+                used: AtomicU64::new(0),
+                day: Mutex::new(Utc::now().date_naive()),
+            }),
+        }
+    }
+
+    /// 当天的配额用完了就不再往这个节点发请求
+    pub fn exhausted(&self) -> bool {
+        self.daily.as_ref().is_some_and(|daily| {
+            daily.reset_if_new_day();
+            daily.used.load(Ordering::Relaxed) >= daily.limit
+        })
+    }
+
+    /// 等到并发数和速率都允许, 返回的permit在请求结束前不能释放
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let permit = match &self.concurrency {
+            Some(semaphore) => Some(semaphore.acquire().await.expect("semaphore never closed")),
+            None => None,
+        };
+        if let Some(bucket) = &self.bucket {
+            bucket.acquire().await;
+        }
+        if let Some(daily) = &self.daily {
+            daily.used.fetch_add(1, Ordering::Relaxed);
+        }
+        permit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<RateLimit, String> {
+        s.parse::<RpcLimit>().map(|l| l.limit)
+    }
+
+    #[test]
+    fn parse_rpc_limit() {
+        assert_eq!(
+            parse("infura.io=10,burst=20,concurrency=4,daily=100000").unwrap(),
+            RateLimit {
+                per_second: Some(10.0),
+                burst: Some(20),
+                concurrency: Some(4),
+                daily: Some(100000),
+            }
+        );
+        assert_eq!(
+            parse("*=0.5").unwrap(),
+            RateLimit {
+                per_second: Some(0.5),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            parse("x=daily=10").unwrap(),
+            RateLimit {
+                daily: Some(10),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reject_invalid_rpc_limit() {
+        for s in [
+            "infura.io",
+            "=10",
+            "x=0",
+            "x=-1",
+            "x=inf",
+            "x=10,burst=0",
+            "x=10,burst=4294967296",
+            "x=10,concurrency=2305843009213693952",
+            "x=10,daily=",
+            "x=10,timeout=3",
+            "x=10,,burst=2",
+        ] {
+            assert!(parse(s).is_err(), "{s}");
+        }
+        assert_eq!(
+            parse("x=10,burst=0").unwrap_err(),
+            r#"invalid burst "0": must be positive"#
+        );
+    }
+
+    #[test]
+    fn find_takes_the_last_match() {
+        let limits: Vec<RpcLimit> = ["*=1", "infura.io=2", "other=3"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let rate = |url| RpcLimit::find(&limits, url).and_then(|l| l.per_second);
+        assert_eq!(rate("https://mainnet.infura.io/v3"), Some(2.0));
+        assert_eq!(rate("https://bsc.example.org"), Some(1.0));
+        assert_eq!(RpcLimit::find(&[], "x"), None);
+    }
+
+    #[tokio::test]
+    async fn bucket_refills_up_to_capacity() {
+        let bucket = TokenBucket::new(10.0, Some(2));
+        *bucket.state.lock().unwrap() = (0.0, Instant::now() - Duration::from_secs(10));
+        bucket.acquire().await;
+        let (tokens, _) = *bucket.state.lock().unwrap();
+        assert!((tokens - 1.0).abs() < 0.01, "{tokens}");
+    }
+
+    #[tokio::test]
+    async fn bucket_waits_for_a_token() {
+        let bucket = TokenBucket::new(20.0, Some(1));
+        let start = Instant::now();
+        bucket.acquire().await;
+        assert!(start.elapsed() < Duration::from_millis(20));
+        bucket.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(45));
+    }
+
+    #[tokio::test]
+    async fn daily_quota_resets_on_a_new_day() {
+        let limiter = Limiter::new(Some(&RateLimit {
+            daily: Some(2),
+            ..Default::default()
+        }));
+        assert!(!limiter.exhausted());
+        drop(limiter.acquire().await);
+        drop(limiter.acquire().await);
+        assert!(limiter.exhausted());
+
+        let daily = limiter.daily.as_ref().unwrap();
+        *daily.day.lock().unwrap() = Utc::now().date_naive().pred_opt().unwrap();
+        assert!(!limiter.exhausted());
+        assert_eq!(daily.used.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,26 +1,43 @@
 use std::cmp::min;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use chrono::prelude::*;
-use chrono_tz::Asia::Tokyo;
+use ethers::abi::RawLog;
 use ethers::prelude::*;
-use futures_util::TryStreamExt;
-use log::{error, info, warn};
-use rand::prelude::{IteratorRandom, SeedableRng, StdRng};
-use serde_json::Value;
-use sqlx::prelude::*;
-use sqlx::sqlite::SqliteConnectOptions;
-use sqlx::SqliteConnection;
+use log::{debug, error, info, warn};
 use tokio::sync::mpsc;
 use tokio::task::JoinSet;
 
+use common::block::BlockResolver;
 use common::erc20::*;
-use common::{init_logger, Setting};
+use common::message::TokenMessageArg;
+use common::shutdown::Shutdown;
+use common::{metrics, Setting};
 
-use crate::schema::Msg;
-use crate::utils::CustomRetryPolicy;
+use crate::cli::{token_pairs, Cli, Command, ScanArgs};
+use crate::db::Store;
+use crate::export::{export, open_db};
+use crate::follow::follow;
+use crate::pool::ProviderPool;
+use crate::progress::{init_logger, Progress};
+use crate::reconcile::reconcile;
+use crate::schema::{
+    ApprovalRow, DbMsg, EventSet, MalformedRow, Msg, RangeResult, TransferRow, TxRow,
+};
+use crate::utils::{is_range_limit_error, AdaptiveStep};
 
+mod breaker;
+mod cli;
+mod db;
+mod export;
+mod follow;
+mod limit;
+mod pool;
+mod progress;
+mod reconcile;
+mod retry;
 mod schema;
 mod utils;
 
@@ -28,156 +45,392 @@ static SETTING: Lazy<Setting, fn() -> Setting> = Lazy::new(Setting::init);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse_and_validate();
     init_logger();
-    let token_addr = "0xce634F8225f3276183FdCace7B97C817eAABaaaD";
-    let jp_log_date = "2023-11-28";
-    let step = 100_u64;
-    let batch_size = SETTING.rpc_list.len() * 4;
+    match cli.command {
+        Command::Scan(args) => scan(args).await?,
+        Command::Follow(args) => follow(args).await?,
+        Command::Reconcile(args) => reconcile(args).await?,
+        Command::Export(args) => {
+            let output = args
+                .output
+                .unwrap_or_else(|| args.db.with_extension(args.format.extension()));
+            let mut db = open_db(&args.db).await?;
+            let count = export(&mut db, args.format, &output).await?;
+            info!("exported {count} rows to {}", output.display());
+        }
+    }
+    Ok(())
+}
+
+async fn scan(args: ScanArgs) -> anyhow::Result<()> {
+    let contracts: Arc<[Address]> = args.tokens.iter().map(|t| t.address).collect();
+    let batch_size = args.worker_count(SETTING.rpc_list.len());
+    // 前后多扫一段, gen_time在当天但是过了0点才上链的日志也要扫到
+    let (window_start, window_end) = args.window.block_window();
+    for token in &args.tokens {
+        info!("token {}={:?}", token.label, token.address);
+    }
+    info!("window={window_start} ~ {window_end}, workers={batch_size}");
 
     let t1 = Local::now();
+    if let Some(addr) = &SETTING.metrics_addr {
+        metrics::serve(addr).await?;
+    }
+    // 所有worker共用一个节点池, 慢的或坏掉的节点只会少分到请求
+    let w3 = ProviderPool::provider(&SETTING.rpc_list, args.pool.options()?)?;
+    let resolver = BlockResolver::new(w3.clone());
+    let Some((from_block, to_block)) = resolver
+        .block_range(
+            window_start.timestamp() as u64,
+            window_end.timestamp() as u64,
+        )
+        .await?
+    else {
+        warn!("no block in the window yet");
+        return Ok(());
+    };
+    let safe_block = resolver
+        .latest()
+        .await?
+        .0
+        .saturating_sub(args.confirmations);
+    info!("from_block={from_block}, to_block={to_block}, safe_block={safe_block}");
+
+    let events = args.event_set();
+    let mut store = Store::open(&args.output)
+        .await?
+        .with_events(events)
+        .with_tokens(&token_pairs(&args.tokens))
+        .await?;
+    store.clear_failed(from_block, to_block).await?;
+    let gaps = store.pending_gaps(from_block, to_block).await?;
+    let total_blocks: u64 = gaps.iter().map(|(start, stop)| stop - start + 1).sum();
+    info!("{total_blocks} blocks in {} gaps to scan", gaps.len());
+
     let mut tasks = JoinSet::new();
     let (s, r) = async_channel::bounded(batch_size);
-    let (dbs, mut dbr) = mpsc::unbounded_channel();
+    let (dbs, mut dbr) = mpsc::unbounded_channel::<DbMsg>();
+    // 未完成(成功或最终失败)的区块数, 归零时关闭队列让worker退出
+    let remaining = Arc::new(AtomicU64::new(total_blocks));
+    if total_blocks == 0 {
+        s.close();
+    }
+    let step = Arc::new(AdaptiveStep::new(args.step, args.min_step, args.max_step));
+    let progress = Progress::new(total_blocks);
+    let reporter = progress.spawn(Duration::from_secs(args.progress_interval));
+
+    // 收到信号后关闭队列, worker做完手上的范围就退出, db任务把结果存完再汇总
+    let shutdown = Shutdown::listen();
+    let s_close = s.clone();
+    let mut shutdown_wait = shutdown.clone();
+    let stop_task = tokio::spawn(async move {
+        shutdown_wait.wait().await;
+        s_close.close();
+    });
 
     let s_produce = s.clone();
+    let step_produce = step.clone();
     let s_task = tokio::spawn(async move {
-        let mut rng = StdRng::from_entropy();
-        let host = reqwest::Url::parse(SETTING.rpc_list.iter().choose(&mut rng).unwrap())?;
-        let client = reqwest::Client::builder()
-            .connect_timeout(Duration::from_secs(5))
-            .timeout(Duration::from_secs(120))
-            .tcp_keepalive(Duration::from_secs(300))
-            .build()?;
-        let provider = Http::new_with_client(host, client);
-        let w3: Arc<Provider<Http>> = Arc::new(Provider::new(provider));
-        // let w3: Arc<Provider<Http>> = Arc::new(Provider::<Http>::try_from(host)?);
-        let current_block = w3.get_block(BlockNumber::Latest).await?.unwrap();
-        let tx_date = jp_log_date.parse::<NaiveDate>()?;
-        let tx_datetime = Tokyo
-            .with_ymd_and_hms(tx_date.year(), tx_date.month(), tx_date.day(), 0, 0, 0)
-            .unwrap();
-        let from_block = current_block.number.unwrap().as_u64()
-            - (current_block.timestamp.as_u64()
-                - (tx_datetime - chrono::Duration::hours(1)).timestamp() as u64)
-                / 3;
-        let to_block = current_block.number.unwrap().as_u64()
-            - (current_block.timestamp.as_u64()
-                - (tx_datetime + chrono::Duration::hours(27)).timestamp() as u64)
-                / 3;
-        info!("from_block={}, to_block={}", from_block, to_block);
-
-        for start in (from_block..=to_block).step_by(step as usize) {
-            let stop = min(start + step - 1, to_block);
-            s_produce.send(Msg { start, stop }).await?;
+        // 按当前步长逐个生成范围, 队列是有界的, 步长的调整很快就会生效
+        for (from, to) in gaps {
+            let mut start = from;
+            while start <= to {
+                let stop = min(start + step_produce.get() - 1, to);
+                s_produce.send(Msg::new(start, stop)).await?;
+                start = stop + 1;
+            }
         }
         Ok::<(), anyhow::Error>(())
     });
 
+    let max_attempts = args.max_attempts;
     for i in 0..batch_size {
+        let s = s.clone();
         let r = r.clone();
         let dbs = dbs.clone();
+        let remaining = remaining.clone();
+        let step = step.clone();
+        let contracts = contracts.clone();
+        let w3 = w3.clone();
+        let shutdown = shutdown.clone();
         tasks.spawn(async move {
-            let host = reqwest::Url::parse(&SETTING.rpc_list[i % SETTING.rpc_list.len()])?;
-            let client = reqwest::Client::builder()
-                .connect_timeout(Duration::from_secs(5))
-                .timeout(Duration::from_secs(120))
-                .tcp_keepalive(Duration::from_secs(300))
-                .gzip(true)
-                .build()?;
-            let provider = Http::new_with_client(host, client);
-            let retry_client = RetryClientBuilder::default()
-                .rate_limit_retries(2)
-                .build(provider, Box::new(CustomRetryPolicy));
-            let w3 = Arc::new(Provider::new(retry_client));
-            let c = Erc20Token::new(token_addr.parse::<Address>()?, w3.clone());
+            let c = Erc20Token::new(contracts[0], w3);
             while let Ok(msg) = r.recv().await {
-                info!("worker {i}, get_started={}, stop={}", msg.start, msg.stop);
-                let event = c.event::<TokenTransferFilter>();
-                let rsp = event
-                    .from_block(msg.start)
-                    .to_block(msg.stop)
-                    .query_with_meta()
-                    .await;
-                match rsp {
-                    Ok(logs) => {
-                        for (decoded_log, meta) in logs {
-                            let message: Value = serde_json::from_str(&decoded_log.message)?;
-                            // if let Some(gen_time) = message.get("gen_time") {
-                            //     let str_gen_time =
-                            //         gen_time.as_str().map(String::from).unwrap_or_default();
-                            //     if &str_gen_time[..10] != jp_log_date {
-                            //         // warn!("get different tx time {str_gen_time}");
-                            //         // continue;
-                            //     }
-                            // }
-                            let tag_id = message["tag_id"].as_str().map(String::from);
-                            let log_time = message["gen_time"].as_str().map(String::from);
-                            let block = meta.block_number.as_u64();
-                            let tx_hash = format!("{:?}", meta.transaction_hash);
-                            dbs.send((tag_id, tx_hash, block, log_time))?;
-                        }
+                if shutdown.is_triggered() {
+                    break;
+                }
+                metrics::channel_backlog("ranges", r.len());
+                debug!("worker {i}, get_started={}, stop={}", msg.start, msg.stop);
+                let db_msg = match fetch_range(&c, &contracts, msg, safe_block, events).await {
+                    Ok(result) => {
+                        step.observe(msg.blocks(), result.logs());
+                        DbMsg::Done(result)
+                    }
+                    Err(e) if msg.blocks() > 1 && is_range_limit_error(&format!("{e:#}")) => {
+                        // 对半拆分重新入队, 不计入失败次数
+                        let new_step = step.shrink(msg.blocks());
+                        let (left, right) = msg.split();
+                        warn!(
+                            "worker {i} range limit, get_started={}, stop={}, split, step={new_step}",
+                            msg.start, msg.stop
+                        );
+                        let s = s.clone();
+                        tokio::spawn(async move {
+                            let _ = s.send(left).await;
+                            let _ = s.send(right).await;
+                        });
+                        continue;
+                    }
+                    Err(e) if msg.attempt + 1 < max_attempts => {
+                        let delay = retry_backoff(msg.attempt);
+                        warn!(
+                            "worker {i} error: {e}, get_started={}, stop={}, attempt {} retry in {delay:?}",
+                            msg.start,
+                            msg.stop,
+                            msg.attempt + 1
+                        );
+                        let s = s.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            let _ = s.send(msg.retry()).await;
+                        });
+                        continue;
                     }
                     Err(e) => {
                         error!(
-                            "worker {i} error:{} {e}, get_started={}, stop={}",
-                            e.is_middleware_error(),
+                            "worker {i} error: {e}, get_started={}, stop={}, give up after {} attempts",
                             msg.start,
-                            msg.stop
+                            msg.stop,
+                            msg.attempt + 1
                         );
+                        DbMsg::Failed {
+                            range: msg,
+                            error: e.to_string(),
+                        }
                     }
+                };
+                if dbs.send(db_msg).is_err() {
+                    r.close();
+                    anyhow::bail!("worker {i}: db task exited");
+                }
+                if remaining.fetch_sub(msg.blocks(), Ordering::SeqCst) == msg.blocks() {
+                    r.close();
                 }
             }
             warn!("worker {i} exited");
             Ok::<(), anyhow::Error>(())
         });
     }
+    let (export_format, output) = (args.export, args.output.clone());
     let dbtask = tokio::spawn(async move {
-        let options = SqliteConnectOptions::new()
-            .filename("txs.db")
-            .create_if_missing(true);
-        let mut db = SqliteConnection::connect_with(&options).await?;
-
-        sqlx::query("CREATE TABLE IF NOT EXISTS transactions(id INTEGER PRIMARY KEY AUTOINCREMENT,tag_id text NOT NULL,hash text NOT NULL,block integer NOT NULL,log_time text)").execute(&mut db).await?;
-        let mut tx = sqlx::Connection::begin(&mut db).await?;
-        let stmt = tx
-            .prepare("INSERT INTO transactions(tag_id,hash,block,log_time) VALUES(?,?,?,?)")
-            .await?;
         while let Some(msg) = dbr.recv().await {
-            stmt.query()
-                .bind(msg.0)
-                .bind(msg.1)
-                .bind(msg.2 as i64)
-                .bind(msg.3)
-                .execute(&mut *tx)
-                .await?;
+            metrics::channel_backlog("db", dbr.len());
+            match msg {
+                DbMsg::Done(result) => {
+                    progress.range_done(result.range.blocks(), result.logs());
+                    store.save_range(result).await?
+                }
+                DbMsg::Failed { range, error } => {
+                    progress.range_failed(range.blocks());
+                    store.save_failed(range, &error).await?
+                }
+            }
         }
-        tx.commit().await?;
-        let count: (i32, i32) =
-            sqlx::query_as("SELECT COUNT(tag_id),COUNT(DISTINCT tag_id) FROM transactions")
-                .fetch_one(&mut db)
-                .await?;
-        warn!("total tx_hash {}, distinct tx_hash {}", count.0, count.1);
-        let mut rows = sqlx::query(
-            "SELECT tag_id, COUNT(*) AS quantity FROM transactions GROUP BY tag_id HAVING quantity>1 limit 5",
-        ).fetch(&mut db);
-        while let Some(row) = rows.try_next().await? {
-            warn!(
-                "{},{}",
-                row.get::<String, _>("tag_id"),
-                row.get::<i32, _>("quantity")
-            )
+        progress.finish();
+        if let Some(reporter) = reporter {
+            reporter.abort();
         }
-        Ok::<(), anyhow::Error>(())
+        store.report().await?;
+        let failed = store.failed_ranges(from_block, to_block).await?;
+        for (start, stop, error) in &failed {
+            error!("failed range start={start}, stop={stop}: {error}");
+        }
+        let missing = store.pending_gaps(from_block, to_block).await?;
+        if let Some(format) = export_format {
+            let path = output.with_extension(format.extension());
+            let count = export(store.connection(), format, &path).await?;
+            info!("exported {count} rows to {}", path.display());
+        }
+        Ok::<_, anyhow::Error>((failed.len(), missing.len()))
     });
     drop(dbs);
     drop(s);
     let _ = tokio::join!(s_task);
-    while tasks.join_next().await.is_some() {}
-    dbtask.await.unwrap()?;
+    while let Some(res) = tasks.join_next().await {
+        if let Ok(Err(e)) = res {
+            error!("{e}");
+        }
+    }
+    let (failed, missing) = dbtask.await??;
+    stop_task.abort();
     info!("run time {} s", (Local::now() - t1).num_seconds());
+    if shutdown.is_triggered() {
+        anyhow::bail!(
+            "scan interrupted, {failed} ranges failed, {missing} gaps missing, rerun to resume"
+        );
+    }
+    if missing > 0 {
+        anyhow::bail!(
+            "scan incomplete, {failed} ranges failed, {missing} gaps missing, rerun to resume"
+        );
+    }
     Ok(())
 }
 
+/// 用一个过滤器查询所有合约在一个区块范围内的日志, 高于`safe_block`的日志标记为未确认
+async fn fetch_range<M: Middleware + 'static>(
+    c: &Erc20Token<M>,
+    contracts: &[Address],
+    msg: Msg,
+    safe_block: u64,
+    events: EventSet,
+) -> anyhow::Result<RangeResult> {
+    // 先取hash再取日志, 中间发生分叉的话下次检查时hash对不上, 会重新扫描
+    let stop_hash = if msg.stop > safe_block {
+        let block = c
+            .client()
+            .get_block(msg.stop)
+            .await
+            .map_err(|e| anyhow::anyhow!("{e}"))?
+            .ok_or_else(|| anyhow::anyhow!("block {} not found", msg.stop))?;
+        block.hash.map(|hash| format!("{hash:?}"))
+    } else {
+        None
+    };
+    let mut result = RangeResult {
+        range: msg,
+        rows: Vec::new(),
+        transfers: Vec::new(),
+        approvals: Vec::new(),
+        malformed: Vec::new(),
+        stop_hash,
+    };
+    match events {
+        EventSet::TokenTransfer => {
+            let logs = c
+                .event::<TokenTransferFilter>()
+                .address(ValueOrArray::Array(contracts.to_vec()))
+                .from_block(msg.start)
+                .to_block(msg.stop)
+                .query_with_meta()
+                .await?;
+            for (decoded_log, meta) in logs {
+                push_token_transfer(&mut result, decoded_log, &meta, safe_block);
+            }
+        }
+        EventSet::All => {
+            // 一次取回合约的全部日志, 不认识的事件跳过
+            let filter = c
+                .events()
+                .address(ValueOrArray::Array(contracts.to_vec()))
+                .from_block(msg.start)
+                .to_block(msg.stop)
+                .filter;
+            let logs = c
+                .client()
+                .get_logs(&filter)
+                .await
+                .map_err(|e| anyhow::anyhow!("{e}"))?;
+            for log in logs {
+                let meta = LogMeta::from(&log);
+                let block = meta.block_number.as_u64();
+                match Erc20TokenEvents::decode_log(&RawLog::from(log)) {
+                    Ok(Erc20TokenEvents::TokenTransferFilter(e)) => {
+                        push_token_transfer(&mut result, e, &meta, safe_block)
+                    }
+                    Ok(Erc20TokenEvents::TransferFilter(e)) => result.transfers.push(TransferRow {
+                        hash: format!("{:?}", meta.transaction_hash),
+                        log_index: meta.log_index.as_u64(),
+                        block,
+                        block_hash: format!("{:?}", meta.block_hash),
+                        from_addr: format!("{:?}", e.from),
+                        contract: format!("{:?}", meta.address),
+                        to_addr: format!("{:?}", e.to),
+                        value: e.value.to_string(),
+                        confirmed: block <= safe_block,
+                    }),
+                    Ok(Erc20TokenEvents::ApprovalFilter(e)) => result.approvals.push(ApprovalRow {
+                        hash: format!("{:?}", meta.transaction_hash),
+                        log_index: meta.log_index.as_u64(),
+                        block,
+                        block_hash: format!("{:?}", meta.block_hash),
+                        owner: format!("{:?}", e.owner),
+                        spender: format!("{:?}", e.spender),
+                        contract: format!("{:?}", meta.address),
+                        value: e.value.to_string(),
+                        confirmed: block <= safe_block,
+                    }),
+                    Err(_) => warn!(
+                        "unknown event in tx {:?}, log_index {}",
+                        meta.transaction_hash, meta.log_index
+                    ),
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// message解析成TokenMessageArg, 失败的日志记到malformed, 不影响同一范围的其它日志
+fn push_token_transfer(
+    result: &mut RangeResult,
+    decoded_log: TokenTransferFilter,
+    meta: &LogMeta,
+    safe_block: u64,
+) {
+    let block = meta.block_number.as_u64();
+    let hash = format!("{:?}", meta.transaction_hash);
+    let contract = format!("{:?}", meta.address);
+    match parse_message(&decoded_log.message) {
+        Ok(args) => result.rows.push(TxRow {
+            contract,
+            tag_id: args.tag_id,
+            hash,
+            log_index: meta.log_index.as_u64(),
+            block,
+            block_hash: format!("{:?}", meta.block_hash),
+            log_time: args.gen_time,
+            to_addr: format!("{:?}", decoded_log.to),
+            value: decoded_log.value.to_string(),
+            message: decoded_log.message,
+            confirmed: block <= safe_block,
+        }),
+        Err(e) => {
+            warn!(
+                "malformed message in tx {hash}, log_index {}: {e:#}",
+                meta.log_index
+            );
+            result.malformed.push(MalformedRow {
+                contract,
+                hash,
+                log_index: meta.log_index.as_u64(),
+                block,
+                block_hash: format!("{:?}", meta.block_hash),
+                message: decoded_log.message,
+                error: format!("{e:#}"),
+                confirmed: block <= safe_block,
+            });
+        }
+    }
+}
+
+fn parse_message(message: &str) -> anyhow::Result<TokenMessageArg> {
+    let args: TokenMessageArg = serde_json::from_str(message)?;
+    if args.tag_id.is_empty() {
+        anyhow::bail!("empty tag_id");
+    }
+    if args.gen_time.is_empty() {
+        anyhow::bail!("empty gen_time");
+    }
+    Ok(args)
+}
+
+/// 2s, 4s, 8s ... 最长60s
+fn retry_backoff(attempt: u32) -> Duration {
+    Duration::from_secs(2_u64.saturating_pow(attempt + 1)).min(Duration::from_secs(60))
+}
+
 pub trait AnyExt {
     fn type_name(&self) -> &'static str;
 }
@@ -0,0 +1,176 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use ethers::prelude::{JsonRpcClient, Provider, ProviderError, RpcError};
+use ethers::providers::RetryClientError;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use common::metrics;
+
+use crate::breaker::BreakerOptions;
+use crate::limit::{Limiter, RpcLimit};
+use crate::retry::{Params, RetrySettings, RetryingHttp, RetryingHttpError};
+
+pub type PoolProvider = Provider<ProviderPool>;
+
+/// 平均值的平滑系数, 越大越看重最近的请求
+const EWMA_ALPHA: f64 = 0.2;
+/// 还没有请求过的节点按这个延迟估算
+const INITIAL_LATENCY_MS: f64 = 200.0;
+
+#[derive(Debug, Clone, Default)]
+pub struct PoolOptions {
+    /// 每个节点的熔断参数
+    pub breaker: BreakerOptions,
+    /// 按url匹配的限流配置
+    pub limits: Vec<RpcLimit>,
+    pub retry: RetrySettings,
+}
+
+#[derive(Debug)]
+struct Health {
+    latency_ms: f64,
+    error_rate: f64,
+}
+
+struct Endpoint {
+    url: String,
+    client: RetryingHttp,
+    /// 包括还在等限流的请求
+    in_flight: AtomicUsize,
+    health: Mutex<Health>,
+}
+
+impl Endpoint {
+    /// 越小越好, 延迟按并发数和错误率放大
+    fn score(&self) -> f64 {
+        let health = self.health.lock().unwrap();
+        let in_flight = self.in_flight.load(Ordering::Relaxed) as f64;
+        health.latency_ms * (1.0 + in_flight) * (1.0 + 10.0 * health.error_rate)
+    }
+}
+
+/// 多个rpc节点共享的provider, 按延迟和错误率选节点, 熔断器断开的节点冷却一段时间后再用
+pub struct ProviderPool {
+    endpoints: Vec<Endpoint>,
+    options: PoolOptions,
+}
+
+impl Debug for ProviderPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProviderPool")
+            .field(
+                "endpoints",
+                &self.endpoints.iter().map(|e| &e.url).collect::<Vec<_>>(),
+            )
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl ProviderPool {
+    pub fn new(urls: &[String], options: PoolOptions) -> anyhow::Result<Self> {
+        anyhow::ensure!(!urls.is_empty(), "rpc_list is empty");
+        let endpoints = urls
+            .iter()
+            .map(|url| {
+                Ok(Endpoint {
+                    url: url.clone(),
+                    client: RetryingHttp::new(
+                        url,
+                        options.retry.for_url(url).clone(),
+                        options.breaker,
+                        Limiter::new(RpcLimit::find(&options.limits, url)),
+                    )?,
+                    in_flight: AtomicUsize::new(0),
+                    health: Mutex::new(Health {
+                        latency_ms: INITIAL_LATENCY_MS,
+                        error_rate: 0.0,
+                    }),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { endpoints, options })
+    }
+
+    pub fn provider(urls: &[String], options: PoolOptions) -> anyhow::Result<Arc<PoolProvider>> {
+        Ok(Arc::new(Provider::new(Self::new(urls, options)?)))
+    }
+
+    /// 熔断器放行的节点里分数最低的, 当天配额用完的节点不参与
+    fn pick(&self, skip: &[usize]) -> Option<usize> {
+        (0..self.endpoints.len())
+            .filter(|i| !skip.contains(i))
+            .map(|i| (i, &self.endpoints[i]))
+            .filter(|(_, e)| e.client.breaker().available() && !e.client.limiter().exhausted())
+            .map(|(i, e)| (i, e.score()))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| i)
+    }
+
+    /// json-rpc错误说明节点是好的, 只是请求本身有问题
+    fn record(&self, i: usize, elapsed: Duration, ok: bool) {
+        let endpoint = &self.endpoints[i];
+        metrics::rpc_request(&endpoint.url, elapsed);
+        let mut health = endpoint.health.lock().unwrap();
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        health.latency_ms += EWMA_ALPHA * (ms - health.latency_ms);
+        health.error_rate += EWMA_ALPHA * (if ok { 0.0 } else { 1.0 } - health.error_rate);
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for ProviderPool {
+    type Error = RetryClientError;
+
+    /// 节点不可用时换下一个节点, 所有节点都试过后返回最后的错误
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params = Params::new(params).map_err(RetryClientError::SerdeJson)?;
+        let mut tried = Vec::with_capacity(self.endpoints.len());
+        let mut last_err = None;
+        loop {
+            let Some(i) = self.pick(&tried) else {
+                return Err(RetryClientError::ProviderError(last_err.unwrap_or_else(
+                    || {
+                        ProviderError::CustomError(
+                            "no rpc endpoint available: circuits open or daily quota used up"
+                                .to_string(),
+                        )
+                    },
+                )));
+            };
+            tried.push(i);
+            let endpoint = &self.endpoints[i];
+            endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+            let start = Instant::now();
+            let result = params.send(&endpoint.client, method).await;
+            endpoint.in_flight.fetch_sub(1, Ordering::Relaxed);
+            match result {
+                Ok(r) => {
+                    self.record(i, start.elapsed(), true);
+                    return Ok(r);
+                }
+                Err(e) if e.as_error_response().is_some() => {
+                    self.record(i, start.elapsed(), true);
+                    return Err(RetryClientError::ProviderError(e.into()));
+                }
+                // 被熔断器挡下的请求没有发出去, 不计入健康统计
+                Err(e @ RetryingHttpError::CircuitOpen(_)) => last_err = Some(e.into()),
+                Err(e) => {
+                    self.record(i, start.elapsed(), false);
+                    warn!("rpc {} {method} error: {e}", endpoint.url);
+                    last_err = Some(e.into());
+                }
+            }
+        }
+    }
+}
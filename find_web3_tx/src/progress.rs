@@ -0,0 +1,171 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use indicatif::{HumanDuration, ProgressBar, ProgressStyle};
+use log::{info, Log, Metadata, Record};
+use tokio::task::JoinHandle;
+
+static LOGGER: OnceLock<BarLogger> = OnceLock::new();
+
+/// 和stderr上的进度条共用终端, 打日志前先把进度条收起来, 不然两边会互相覆盖
+struct BarLogger {
+    inner: Box<dyn Log>,
+    bar: Mutex<Option<ProgressBar>>,
+}
+
+impl Log for BarLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        let bar = self.bar.lock().unwrap().clone();
+        match bar {
+            Some(bar) => bar.suspend(|| self.inner.log(record)),
+            None => self.inner.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// 代替`common::init_logger`, 格式一样, 显示进度条时不会和它抢同一行
+pub fn init_logger() {
+    let logger = common::logger_builder().build();
+    log::set_max_level(logger.filter());
+    let logger = LOGGER.get_or_init(|| BarLogger {
+        inner: Box::new(logger),
+        bar: Mutex::new(None),
+    });
+    log::set_logger(logger).expect("logger is initialized once");
+}
+
+fn set_logger_bar(bar: Option<ProgressBar>) {
+    if let Some(logger) = LOGGER.get() {
+        *logger.bar.lock().unwrap() = bar;
+    }
+}
+
+/// 扫描的整体进度, stderr是终端时显示进度条, 否则定时打一行日志
+pub struct Progress {
+    total: u64,
+    blocks: AtomicU64,
+    logs: AtomicU64,
+    failed: AtomicU64,
+    start: Instant,
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    pub fn new(total: u64) -> Arc<Self> {
+        let bar = std::io::stderr().is_terminal().then(|| {
+            let bar = ProgressBar::new(total);
+            bar.set_style(
+                ProgressStyle::with_template(
+                    "{elapsed_precise} [{wide_bar}] {pos}/{len} blocks, eta {eta}, {msg}",
+                )
+                .expect("valid template"),
+            );
+            set_logger_bar(Some(bar.clone()));
+            bar
+        });
+        Arc::new(Self {
+            total,
+            blocks: AtomicU64::new(0),
+            logs: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            start: Instant::now(),
+            bar,
+        })
+    }
+
+    pub fn range_done(&self, blocks: u64, logs: usize) {
+        self.blocks.fetch_add(blocks, Ordering::Relaxed);
+        self.logs.fetch_add(logs as u64, Ordering::Relaxed);
+        self.update_bar(blocks);
+    }
+
+    /// 放弃的范围也算处理完, 不然eta永远到不了
+    pub fn range_failed(&self, blocks: u64) {
+        self.blocks.fetch_add(blocks, Ordering::Relaxed);
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.update_bar(blocks);
+    }
+
+    fn update_bar(&self, blocks: u64) {
+        if let Some(bar) = &self.bar {
+            bar.set_message(format!(
+                "{} logs, {} failed, {:.1} blocks/s",
+                self.logs.load(Ordering::Relaxed),
+                self.failed.load(Ordering::Relaxed),
+                self.rate()
+            ));
+            bar.inc(blocks);
+        }
+    }
+
+    /// 没有进度条时每隔`interval`打一行进度
+    pub fn spawn(self: &Arc<Self>, interval: Duration) -> Option<JoinHandle<()>> {
+        if self.bar.is_some() {
+            return None;
+        }
+        let progress = self.clone();
+        Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                progress.log();
+            }
+        }))
+    }
+
+    /// 进度条收掉后和没有进度条时一样打一行最终的进度
+    pub fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+            set_logger_bar(None);
+        }
+        self.log();
+    }
+
+    /// 从开始到现在的平均速度
+    fn rate(&self) -> f64 {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.blocks.load(Ordering::Relaxed) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    fn log(&self) {
+        let blocks = self.blocks.load(Ordering::Relaxed);
+        let rate = self.rate();
+        let eta = if blocks >= self.total {
+            "done".to_string()
+        } else if rate > 0.0 {
+            HumanDuration(Duration::from_secs_f64((self.total - blocks) as f64 / rate)).to_string()
+        } else {
+            "unknown".to_string()
+        };
+        info!(
+            "progress {blocks}/{} blocks ({:.1}%), {} logs, {} failed ranges, {rate:.1} blocks/s, eta {eta}",
+            self.total,
+            if self.total == 0 {
+                100.0
+            } else {
+                blocks as f64 * 100.0 / self.total as f64
+            },
+            self.logs.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+        );
+    }
+}
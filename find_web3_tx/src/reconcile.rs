@@ -0,0 +1,369 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use chrono::Utc;
+use log::{info, warn};
+use serde::Serialize;
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection};
+
+use common::block::BlockResolver;
+use common::create_pool;
+use common::model::{FromRow, TransactionPool};
+
+use crate::cli::ReconcileArgs;
+use crate::db::{gaps_between, merge_ranges};
+use crate::export::{open_db, table_columns};
+use crate::pool::{PoolOptions, ProviderPool};
+use crate::SETTING;
+
+const POOL_TABLE: &str = "transaction_pool";
+/// sqlite单条语句的参数个数有上限, 按tag_id查询时分批
+const TAG_ID_CHUNK: usize = 500;
+
+/// 链上的一条TokenTransfer日志
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ChainLog {
+    pub tag_id: String,
+    pub hash: String,
+    pub log_index: Option<i64>,
+    pub block: i64,
+    pub contract: Option<String>,
+    pub confirmed: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolEntry {
+    pub tag_id: String,
+    pub status: String,
+    pub tx_hash: Option<String>,
+    pub block_number: Option<i64>,
+}
+
+impl From<&TransactionPool> for PoolEntry {
+    fn from(row: &TransactionPool) -> Self {
+        Self {
+            tag_id: row.tag_id.clone(),
+            status: row.status.to_string(),
+            tx_hash: row.tx_hash.clone(),
+            block_number: row.block_number,
+        }
+    }
+}
+
+/// pool记录的hash或区块和链上日志对不上
+#[derive(Debug, Serialize)]
+pub struct Mismatch {
+    pub tag_id: String,
+    pub fields: Vec<&'static str>,
+    pub pool: PoolEntry,
+    pub chain: ChainLog,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Duplicate {
+    pub tag_id: String,
+    /// "pool"或"chain"
+    pub source: &'static str,
+    pub count: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub pool_rows: usize,
+    pub chain_logs: usize,
+    pub missing_on_chain: usize,
+    pub missing_in_pool: usize,
+    pub mismatched: usize,
+    pub duplicate_tag_ids: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub window_start: String,
+    pub window_end: String,
+    pub from_block: u64,
+    pub to_block: u64,
+    /// 窗口内还没扫描的区块, 这些范围的对账结果不可信
+    pub unscanned_ranges: Vec<(u64, u64)>,
+    pub summary: Summary,
+    /// pool里成功但链上找不到日志
+    pub missing_on_chain: Vec<PoolEntry>,
+    /// 链上有日志但pool里没有记录
+    pub missing_in_pool: Vec<ChainLog>,
+    pub mismatched: Vec<Mismatch>,
+    pub duplicate_tag_ids: Vec<Duplicate>,
+}
+
+/// 用时间窗口内pool的记录和扫描到的日志互相对账,
+/// 两边都按tag_id到对方的全量数据里找, 避免窗口边界上的记录被误报
+pub async fn reconcile(args: ReconcileArgs) -> anyhow::Result<()> {
+    let (window_start, window_end) = args.window.time_window();
+    // 只读打开, 不建表也不升级旧版本的表结构
+    let mut db = open_db(&args.db).await?;
+    let w3 = ProviderPool::provider(&SETTING.rpc_list, PoolOptions::default())?;
+    let resolver = BlockResolver::new(w3);
+    // 和scan一样带上前后的余量, 过了0点才上链的日志也算在窗口里
+    let (scan_start, scan_end) = args.window.block_window();
+    let Some((from_block, to_block)) = resolver
+        .block_range(scan_start.timestamp() as u64, scan_end.timestamp() as u64)
+        .await?
+    else {
+        anyhow::bail!("no block in the window yet");
+    };
+    info!("window={window_start} ~ {window_end}, from_block={from_block}, to_block={to_block}");
+
+    let unscanned_ranges = unscanned_gaps(&mut db, from_block, to_block).await?;
+    if !unscanned_ranges.is_empty() {
+        warn!(
+            "{} ranges of the window are not scanned",
+            unscanned_ranges.len()
+        );
+    }
+    let select = log_query(&mut db).await?;
+    let chain_logs = logs_between(&mut db, &select, from_block, to_block).await?;
+
+    let pool = create_pool(&SETTING.db).await;
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            &format!("SELECT * FROM {POOL_TABLE} WHERE created_at>=$1 AND created_at<$2"),
+            &[
+                &window_start.with_timezone(&Utc),
+                &window_end.with_timezone(&Utc),
+            ],
+        )
+        .await?;
+    let pool_rows = rows
+        .iter()
+        .map(TransactionPool::try_from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut report = Report {
+        window_start: window_start.to_rfc3339(),
+        window_end: window_end.to_rfc3339(),
+        from_block,
+        to_block,
+        unscanned_ranges,
+        summary: Summary {
+            pool_rows: pool_rows.len(),
+            chain_logs: chain_logs.len(),
+            ..Default::default()
+        },
+        missing_on_chain: Vec::new(),
+        missing_in_pool: Vec::new(),
+        mismatched: Vec::new(),
+        duplicate_tag_ids: Vec::new(),
+    };
+
+    // 窗口外的日志也可能对应窗口内的pool记录, 反过来也一样
+    let window_tags: HashSet<&str> = chain_logs.iter().map(|l| l.tag_id.as_str()).collect();
+    let outside: Vec<String> = pool_rows
+        .iter()
+        .filter(|p| !window_tags.contains(p.tag_id.as_str()))
+        .map(|p| p.tag_id.clone())
+        .collect();
+    let mut chain_by_tag: HashMap<String, Vec<ChainLog>> = HashMap::new();
+    for log in chain_logs
+        .iter()
+        .cloned()
+        .chain(logs_with_tag_ids(&mut db, &select, &outside).await?)
+    {
+        chain_by_tag
+            .entry(log.tag_id.clone())
+            .or_default()
+            .push(log);
+    }
+
+    let pool_tags: HashSet<&str> = pool_rows.iter().map(|p| p.tag_id.as_str()).collect();
+    let unknown: Vec<String> = window_tags
+        .iter()
+        .filter(|tag| !pool_tags.contains(*tag))
+        .map(|tag| tag.to_string())
+        .collect();
+    let found = if unknown.is_empty() {
+        Vec::new()
+    } else {
+        conn.query(
+            &format!("SELECT * FROM {POOL_TABLE} WHERE tag_id = ANY($1)"),
+            &[&unknown],
+        )
+        .await?
+        .iter()
+        .map(TransactionPool::try_from_row)
+        .collect::<Result<Vec<_>, _>>()?
+    };
+    let known: HashSet<&str> = pool_tags
+        .iter()
+        .copied()
+        .chain(found.iter().map(|p| p.tag_id.as_str()))
+        .collect();
+    report.missing_in_pool = chain_logs
+        .iter()
+        .filter(|l| !known.contains(l.tag_id.as_str()))
+        .cloned()
+        .collect();
+
+    for row in &pool_rows {
+        let Some(logs) = chain_by_tag.get(&row.tag_id) else {
+            // status在开启pg-with-enum时是StatusChoice, 统一按显示的字符串比较
+            let entry = PoolEntry::from(row);
+            if entry.status == "success" {
+                report.missing_on_chain.push(entry);
+            }
+            continue;
+        };
+        // 有重复日志时取hash一致的那条比较
+        let log = row
+            .tx_hash
+            .as_ref()
+            .and_then(|hash| logs.iter().find(|l| l.hash.eq_ignore_ascii_case(hash)))
+            .unwrap_or(&logs[0]);
+        let mut fields = Vec::new();
+        if matches!(&row.tx_hash, Some(hash) if !hash.eq_ignore_ascii_case(&log.hash)) {
+            fields.push("tx_hash");
+        }
+        if matches!(row.block_number, Some(block) if block != log.block) {
+            fields.push("block_number");
+        }
+        if !fields.is_empty() {
+            report.mismatched.push(Mismatch {
+                tag_id: row.tag_id.clone(),
+                fields,
+                pool: row.into(),
+                chain: log.clone(),
+            });
+        }
+    }
+
+    let mut pool_counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for row in &pool_rows {
+        *pool_counts.entry(&row.tag_id).or_default() += 1;
+    }
+    let mut chain_counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for log in &chain_logs {
+        *chain_counts.entry(&log.tag_id).or_default() += 1;
+    }
+    for (source, counts) in [("pool", pool_counts), ("chain", chain_counts)] {
+        report
+            .duplicate_tag_ids
+            .extend(
+                counts
+                    .into_iter()
+                    .filter(|(_, count)| *count > 1)
+                    .map(|(tag_id, count)| Duplicate {
+                        tag_id: tag_id.to_string(),
+                        source,
+                        count,
+                    }),
+            );
+    }
+
+    report.summary.missing_on_chain = report.missing_on_chain.len();
+    report.summary.missing_in_pool = report.missing_in_pool.len();
+    report.summary.mismatched = report.mismatched.len();
+    report.summary.duplicate_tag_ids = report.duplicate_tag_ids.len();
+    info!("{:?}", report.summary);
+
+    match &args.output {
+        Some(path) => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            serde_json::to_writer_pretty(&mut writer, &report)?;
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+            info!("report written to {}", path.display());
+        }
+        None => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+/// 按库里已有checkpoint的合约计算没扫描的范围, 没有任何checkpoint时整个范围都算没扫描;
+/// 全部事件和只查TokenTransfer的checkpoint都包含TokenTransfer日志, 不区分
+async fn unscanned_gaps(
+    db: &mut SqliteConnection,
+    from_block: u64,
+    to_block: u64,
+) -> anyhow::Result<Vec<(u64, u64)>> {
+    if table_columns(db, "checkpoints").await?.is_empty() {
+        return Ok(vec![(from_block, to_block)]);
+    }
+    let tokens: Vec<String> = sqlx::query_scalar("SELECT DISTINCT token FROM checkpoints")
+        .fetch_all(&mut *db)
+        .await?;
+    if tokens.is_empty() {
+        return Ok(vec![(from_block, to_block)]);
+    }
+    let mut gaps = Vec::new();
+    for token in &tokens {
+        let done: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT start,stop FROM checkpoints WHERE token=? AND status='done' AND stop>=? AND start<=? ORDER BY start",
+        )
+        .bind(token)
+        .bind(from_block as i64)
+        .bind(to_block as i64)
+        .fetch_all(&mut *db)
+        .await?;
+        gaps.extend(gaps_between(&done, from_block, to_block));
+    }
+    Ok(merge_ranges(gaps))
+}
+
+/// 旧版本的文件缺少后来加的列, 缺的列按NULL和已确认处理
+async fn log_query(db: &mut SqliteConnection) -> anyhow::Result<String> {
+    let columns = table_columns(db, "transactions").await?;
+    anyhow::ensure!(!columns.is_empty(), "no transactions table in the database");
+    let column = |name: &str, missing: &str| {
+        if columns.iter().any(|c| c == name) {
+            name.to_string()
+        } else {
+            format!("{missing} AS {name}")
+        }
+    };
+    Ok(format!(
+        "SELECT tag_id,hash,{},block,{},{} FROM transactions",
+        column("log_index", "NULL"),
+        column("contract", "NULL"),
+        column("confirmed", "1"),
+    ))
+}
+
+async fn logs_between(
+    db: &mut SqliteConnection,
+    select: &str,
+    from_block: u64,
+    to_block: u64,
+) -> anyhow::Result<Vec<ChainLog>> {
+    let logs = sqlx::query_as(&format!(
+        "{select} WHERE block>=? AND block<=? ORDER BY block,log_index"
+    ))
+    .bind(from_block as i64)
+    .bind(to_block as i64)
+    .fetch_all(db)
+    .await?;
+    Ok(logs)
+}
+
+async fn logs_with_tag_ids(
+    db: &mut SqliteConnection,
+    select: &str,
+    tag_ids: &[String],
+) -> anyhow::Result<Vec<ChainLog>> {
+    let mut logs = Vec::new();
+    for chunk in tag_ids.chunks(TAG_ID_CHUNK) {
+        let mut query: QueryBuilder<Sqlite> =
+            QueryBuilder::new(format!("{select} WHERE tag_id IN ("));
+        let mut separated = query.separated(",");
+        for tag_id in chunk {
+            separated.push_bind(tag_id);
+        }
+        query.push(")");
+        logs.extend(
+            query
+                .build_query_as::<ChainLog>()
+                .fetch_all(&mut *db)
+                .await?,
+        );
+    }
+    Ok(logs)
+}
@@ -0,0 +1,645 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use ethers::prelude::{
+    Http, HttpClientError, JsonRpcClient, JsonRpcError, ProviderError, RetryPolicy, RpcError,
+};
+use log::debug;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use common::metrics;
+
+use crate::breaker::{BreakerOptions, CircuitBreaker};
+use crate::limit::Limiter;
+use crate::utils::is_range_limit_error;
+
+/// 一条可重试的错误, code和message都设置时两个都要满足, message不区分大小写按子串匹配
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RetryRule {
+    #[serde(default)]
+    pub code: Option<i64>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+impl RetryRule {
+    fn matches(&self, err: &JsonRpcError) -> bool {
+        if self.code.is_none() && self.message.is_none() {
+            return false;
+        }
+        self.code.is_none_or(|code| code == err.code)
+            && self
+                .message
+                .as_ref()
+                .is_none_or(|message| err.message.to_lowercase().contains(&message.to_lowercase()))
+    }
+}
+
+/// 一个rpc节点的重试配置, 没写的字段用默认值
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub rules: Vec<RetryRule>,
+    /// 连接失败, 超时等网络错误是否重试
+    pub retry_transport: bool,
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub multiplier: f64,
+    /// 0到1, 每次等待时间在`backoff * (1 ± jitter)`之间随机
+    pub jitter: f64,
+    /// 从第一次请求开始算, 超过这个时间不再重试
+    pub max_elapsed_ms: u64,
+}
+
+impl Default for RetryConfig {
+    /// 和ethers默认的HttpRateLimitRetryPolicy认的错误一样
+    fn default() -> Self {
+        let rule = |code: Option<i64>, message: Option<&str>| RetryRule {
+            code,
+            message: message.map(String::from),
+        };
+        Self {
+            rules: vec![
+                // alchemy
+                rule(Some(429), None),
+                // infura exceeded project rate limit
+                rule(Some(-32005), None),
+                // alchemy for specific IPs
+                rule(Some(-32016), Some("rate limit")),
+                // infura load balancer issue
+                rule(None, Some("header not found")),
+                // infura out of budget for the day
+                rule(None, Some("daily request count exceeded")),
+            ],
+            retry_transport: true,
+            max_retries: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_elapsed_ms: 60_000,
+        }
+    }
+}
+
+/// 按url子串选用的配置
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetryProfile {
+    pub pattern: String,
+    #[serde(flatten)]
+    pub config: RetryConfig,
+}
+
+/// `--retry-config`的json文件, profiles里第一个匹配url的生效, 都不匹配时用default
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RetrySettings {
+    #[serde(default)]
+    pub default: RetryConfig,
+    #[serde(default)]
+    pub profiles: Vec<RetryProfile>,
+}
+
+impl RetrySettings {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let settings: Self = serde_json::from_str(&text)?;
+        for config in
+            std::iter::once(&settings.default).chain(settings.profiles.iter().map(|p| &p.config))
+        {
+            anyhow::ensure!(
+                (0.0..=1.0).contains(&config.jitter),
+                "jitter must be between 0 and 1"
+            );
+            anyhow::ensure!(config.multiplier >= 1.0, "multiplier must be at least 1");
+        }
+        Ok(settings)
+    }
+
+    pub fn for_url(&self, url: &str) -> &RetryConfig {
+        self.profiles
+            .iter()
+            .find(|p| url.contains(&p.pattern))
+            .map_or(&self.default, |p| &p.config)
+    }
+}
+
+/// some providers send invalid JSON RPC in the error case (no `id:u64`), but the
+/// text should be a `JsonRpcError`
+fn embedded_error(text: &str) -> Option<JsonRpcError> {
+    #[derive(Deserialize)]
+    struct Resp {
+        error: JsonRpcError,
+    }
+
+    serde_json::from_str::<Resp>(text)
+        .ok()
+        .map(|resp| resp.error)
+}
+
+/// 节点自己回了json-rpc错误, 说明节点是活的;
+/// 网络错误和负载均衡返回的html错误页都不算
+pub fn is_json_rpc_reply(error: &HttpClientError) -> bool {
+    match error {
+        HttpClientError::ReqwestError(_) => false,
+        HttpClientError::JsonRpcError(_) => true,
+        HttpClientError::SerdeJson { text, .. } => embedded_error(text).is_some(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// 网络错误
+    Transport,
+    /// 匹配了重试规则, 一般是限流
+    Retryable,
+    /// 范围太大, 重试同一个范围没用, 要拆小
+    RangeLimit,
+    /// 其它错误
+    Fatal,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CustomRetryPolicy {
+    config: RetryConfig,
+}
+
+impl CustomRetryPolicy {
+    pub fn new(config: RetryConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn classify(&self, error: &HttpClientError) -> ErrorClass {
+        match error {
+            HttpClientError::ReqwestError(_) => ErrorClass::Transport,
+            HttpClientError::JsonRpcError(err) => self.classify_json_rpc_error(err),
+            HttpClientError::SerdeJson { text, .. } => match embedded_error(text) {
+                Some(err) => self.classify_json_rpc_error(&err),
+                None => ErrorClass::Fatal,
+            },
+        }
+    }
+
+    fn classify_json_rpc_error(&self, err: &JsonRpcError) -> ErrorClass {
+        // infura uses -32005 for too many results too, retrying the same range never helps
+        if is_range_limit_error(&err.message) {
+            return ErrorClass::RangeLimit;
+        }
+        if self.config.rules.iter().any(|rule| rule.matches(err)) {
+            ErrorClass::Retryable
+        } else {
+            ErrorClass::Fatal
+        }
+    }
+
+    /// 第`attempt`次重试前等待的时间, 从0开始
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let config = &self.config;
+        let base = (config.initial_backoff_ms as f64 * config.multiplier.powi(attempt as i32))
+            .min(config.max_backoff_ms as f64);
+        let factor = if config.jitter > 0.0 {
+            rand::thread_rng().gen_range(1.0 - config.jitter..=1.0 + config.jitter)
+        } else {
+            1.0
+        };
+        Duration::from_millis((base * factor) as u64)
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.config.max_retries
+    }
+
+    pub fn max_elapsed(&self) -> Duration {
+        Duration::from_millis(self.config.max_elapsed_ms)
+    }
+}
+
+impl RetryPolicy<HttpClientError> for CustomRetryPolicy {
+    fn should_retry(&self, error: &HttpClientError) -> bool {
+        match self.classify(error) {
+            ErrorClass::Transport => self.config.retry_transport,
+            ErrorClass::Retryable => true,
+            ErrorClass::RangeLimit | ErrorClass::Fatal => false,
+        }
+    }
+
+    fn backoff_hint(&self, error: &HttpClientError) -> Option<Duration> {
+        if let HttpClientError::JsonRpcError(JsonRpcError { data, .. }) = error {
+            let data = data.as_ref()?;
+
+            // if daily rate limit exceeded, infura returns the requested backoff in the error
+            // response
+            let backoff_seconds = &data["rate"]["backoff_seconds"];
+            // infura rate limit error
+            if let Some(seconds) = backoff_seconds.as_u64() {
+                return Some(Duration::from_secs(seconds));
+            }
+            if let Some(seconds) = backoff_seconds.as_f64() {
+                return Some(Duration::from_secs(seconds as u64 + 1));
+            }
+        }
+
+        None
+    }
+}
+
+/// 请求参数序列化一次后重复使用,
+/// 零大小的参数类型(比如`()`)http transport会省略params字段, 这里保留这个行为
+pub enum Params {
+    Value(Value),
+    Zst,
+}
+
+impl Params {
+    pub fn new<T: Serialize>(params: T) -> Result<Self, serde_json::Error> {
+        if std::mem::size_of::<T>() == 0 {
+            Ok(Params::Zst)
+        } else {
+            serde_json::to_value(params).map(Params::Value)
+        }
+    }
+
+    pub async fn send<C, R>(&self, client: &C, method: &str) -> Result<R, C::Error>
+    where
+        C: JsonRpcClient,
+        R: DeserializeOwned + Send,
+    {
+        match self {
+            Params::Value(params) => client.request(method, params).await,
+            Params::Zst => client.request(method, ()).await,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RetryingHttpError {
+    Http(HttpClientError),
+    /// 熔断器断开, 请求没有发出去
+    CircuitOpen(String),
+}
+
+impl Display for RetryingHttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RetryingHttpError::Http(err) => Display::fmt(err, f),
+            RetryingHttpError::CircuitOpen(url) => write!(f, "rpc {url} circuit is open"),
+        }
+    }
+}
+
+impl std::error::Error for RetryingHttpError {}
+
+impl From<HttpClientError> for RetryingHttpError {
+    fn from(err: HttpClientError) -> Self {
+        RetryingHttpError::Http(err)
+    }
+}
+
+impl RpcError for RetryingHttpError {
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        match self {
+            RetryingHttpError::Http(err) => err.as_error_response(),
+            RetryingHttpError::CircuitOpen(_) => None,
+        }
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        match self {
+            RetryingHttpError::Http(err) => err.as_serde_error(),
+            RetryingHttpError::CircuitOpen(_) => None,
+        }
+    }
+}
+
+impl From<RetryingHttpError> for ProviderError {
+    fn from(err: RetryingHttpError) -> Self {
+        match err {
+            RetryingHttpError::Http(err) => err.into(),
+            err @ RetryingHttpError::CircuitOpen(_) => ProviderError::CustomError(err.to_string()),
+        }
+    }
+}
+
+/// 按`CustomRetryPolicy`重试的http client, 指数退避加随机抖动,
+/// 每次请求前先过熔断器, 连续的网络错误会让后面的请求直接失败, 不再等超时;
+/// 重试也是一次请求, 每次都要经过限流器
+#[derive(Debug)]
+pub struct RetryingHttp {
+    url: String,
+    inner: Http,
+    policy: CustomRetryPolicy,
+    breaker: CircuitBreaker,
+    limiter: Limiter,
+}
+
+impl RetryingHttp {
+    pub fn new(
+        url: &str,
+        config: RetryConfig,
+        breaker: BreakerOptions,
+        limiter: Limiter,
+    ) -> anyhow::Result<Self> {
+        let host = reqwest::Url::parse(url)?;
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(120))
+            .tcp_keepalive(Duration::from_secs(300))
+            .gzip(true)
+            .build()?;
+        Ok(Self {
+            url: url.to_string(),
+            inner: Http::new_with_client(host, client),
+            policy: CustomRetryPolicy::new(config),
+            breaker: CircuitBreaker::new(url, breaker),
+            limiter,
+        })
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    pub fn limiter(&self) -> &Limiter {
+        &self.limiter
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for RetryingHttp {
+    type Error = RetryingHttpError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params = Params::new(params).map_err(|err| HttpClientError::SerdeJson {
+            err,
+            text: String::new(),
+        })?;
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            if !self.breaker.try_acquire() {
+                return Err(RetryingHttpError::CircuitOpen(self.url.clone()));
+            }
+            let permit = self.limiter.acquire().await;
+            let result = params.send(&self.inner, method).await;
+            drop(permit);
+            let err = match result {
+                Ok(r) => {
+                    self.breaker.on_success();
+                    return Ok(r);
+                }
+                Err(err) => err,
+            };
+            if is_json_rpc_reply(&err) {
+                self.breaker.on_success();
+            } else {
+                self.breaker.on_failure();
+            }
+            // 熔断器刚断开或当天配额刚用完时返回真实的错误, 节点池按它记失败
+            if attempt >= self.policy.max_retries()
+                || !self.policy.should_retry(&err)
+                || !self.breaker.available()
+                || self.limiter.exhausted()
+            {
+                return Err(err.into());
+            }
+            let delay = self
+                .policy
+                .backoff_hint(&err)
+                .unwrap_or_else(|| self.policy.backoff(attempt));
+            if start.elapsed() + delay > self.policy.max_elapsed() {
+                return Err(err.into());
+            }
+            debug!("{method} error: {err}, retry {} in {delay:?}", attempt + 1);
+            metrics::rpc_retry(&self.url);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_rpc_error(code: i64, message: &str) -> HttpClientError {
+        HttpClientError::JsonRpcError(JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        })
+    }
+
+    fn serde_error(text: &str) -> HttpClientError {
+        HttpClientError::SerdeJson {
+            err: serde_json::from_str::<u64>("x").unwrap_err(),
+            text: text.to_string(),
+        }
+    }
+
+    fn no_jitter() -> RetryConfig {
+        RetryConfig {
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn transport_error_follows_config() {
+        let err = HttpClientError::ReqwestError(
+            reqwest::Client::new().get("not a url").build().unwrap_err(),
+        );
+        let policy = CustomRetryPolicy::default();
+        assert_eq!(policy.classify(&err), ErrorClass::Transport);
+        assert!(policy.should_retry(&err));
+
+        let policy = CustomRetryPolicy::new(RetryConfig {
+            retry_transport: false,
+            ..Default::default()
+        });
+        assert!(!policy.should_retry(&err));
+    }
+
+    #[test]
+    fn code_rule() {
+        let policy = CustomRetryPolicy::default();
+        let err = json_rpc_error(429, "too many requests");
+        assert_eq!(policy.classify(&err), ErrorClass::Retryable);
+        assert!(policy.should_retry(&err));
+        assert!(policy.should_retry(&json_rpc_error(-32005, "project rate limit")));
+    }
+
+    #[test]
+    fn code_and_message_rule_needs_both() {
+        let policy = CustomRetryPolicy::default();
+        assert!(policy.should_retry(&json_rpc_error(-32016, "Your IP exceeded the Rate Limit")));
+        assert_eq!(
+            policy.classify(&json_rpc_error(-32016, "something else")),
+            ErrorClass::Fatal
+        );
+    }
+
+    #[test]
+    fn message_rule_ignores_case() {
+        let policy = CustomRetryPolicy::default();
+        assert!(policy.should_retry(&json_rpc_error(-32000, "Header not found")));
+        assert!(policy.should_retry(&json_rpc_error(
+            -32000,
+            "daily request count exceeded, request rate limited"
+        )));
+    }
+
+    #[test]
+    fn range_limit_is_not_retried() {
+        let policy = CustomRetryPolicy::default();
+        // -32005 would match the code rule, the range limit check comes first
+        let err = json_rpc_error(-32005, "query returned more than 10000 results");
+        assert_eq!(policy.classify(&err), ErrorClass::RangeLimit);
+        assert!(!policy.should_retry(&err));
+    }
+
+    #[test]
+    fn throttled_limit_exceeded_is_retried() {
+        // bsc dataseed nodes throttle with this, it is not about the block range
+        let policy = CustomRetryPolicy::default();
+        let err = json_rpc_error(-32005, "limit exceeded");
+        assert_eq!(policy.classify(&err), ErrorClass::Retryable);
+        assert!(policy.should_retry(&err));
+    }
+
+    #[test]
+    fn unknown_json_rpc_error_is_fatal() {
+        let policy = CustomRetryPolicy::default();
+        let err = json_rpc_error(-32602, "invalid argument");
+        assert_eq!(policy.classify(&err), ErrorClass::Fatal);
+        assert!(!policy.should_retry(&err));
+    }
+
+    #[test]
+    fn configured_rules_replace_defaults() {
+        let policy = CustomRetryPolicy::new(RetryConfig {
+            rules: vec![RetryRule {
+                code: Some(-32099),
+                message: None,
+            }],
+            ..Default::default()
+        });
+        assert!(policy.should_retry(&json_rpc_error(-32099, "busy")));
+        assert!(!policy.should_retry(&json_rpc_error(429, "too many requests")));
+    }
+
+    #[test]
+    fn empty_rule_matches_nothing() {
+        let policy = CustomRetryPolicy::new(RetryConfig {
+            rules: vec![RetryRule {
+                code: None,
+                message: None,
+            }],
+            ..Default::default()
+        });
+        assert!(!policy.should_retry(&json_rpc_error(-32000, "anything")));
+    }
+
+    #[test]
+    fn html_error_page_is_not_a_json_rpc_reply() {
+        assert!(!is_json_rpc_reply(&serde_error(
+            "<html><body>502 Bad Gateway</body></html>"
+        )));
+        assert!(is_json_rpc_reply(&serde_error(
+            r#"{"error":{"code":429,"message":"rate limited"}}"#
+        )));
+        assert!(is_json_rpc_reply(&json_rpc_error(
+            -32000,
+            "execution reverted"
+        )));
+    }
+
+    #[test]
+    fn serde_error_with_embedded_json_rpc_error() {
+        let policy = CustomRetryPolicy::default();
+        let err = serde_error(r#"{"jsonrpc":"2.0","error":{"code":429,"message":"rate limited"}}"#);
+        assert_eq!(policy.classify(&err), ErrorClass::Retryable);
+        let err = serde_error(r#"{"error":{"code":-32000,"message":"block range is too wide"}}"#);
+        assert_eq!(policy.classify(&err), ErrorClass::RangeLimit);
+    }
+
+    #[test]
+    fn serde_error_without_json_rpc_error_is_fatal() {
+        let policy = CustomRetryPolicy::default();
+        let err = serde_error("<html>502 Bad Gateway</html>");
+        assert_eq!(policy.classify(&err), ErrorClass::Fatal);
+        assert!(!policy.should_retry(&err));
+    }
+
+    #[test]
+    fn infura_backoff_hint() {
+        let policy = CustomRetryPolicy::default();
+        let err = HttpClientError::JsonRpcError(JsonRpcError {
+            code: -32005,
+            message: "daily request count exceeded".to_string(),
+            data: Some(serde_json::json!({"rate": {"backoff_seconds": 30}})),
+        });
+        assert_eq!(policy.backoff_hint(&err), Some(Duration::from_secs(30)));
+        let err = HttpClientError::JsonRpcError(JsonRpcError {
+            code: -32005,
+            message: "daily request count exceeded".to_string(),
+            data: Some(serde_json::json!({"rate": {"backoff_seconds": 1.5}})),
+        });
+        assert_eq!(policy.backoff_hint(&err), Some(Duration::from_secs(2)));
+        assert_eq!(policy.backoff_hint(&json_rpc_error(429, "slow down")), None);
+    }
+
+    #[test]
+    fn exponential_backoff_is_capped() {
+        let policy = CustomRetryPolicy::new(RetryConfig {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            ..no_jitter()
+        });
+        let delays: Vec<u64> = (0..6)
+            .map(|attempt| policy.backoff(attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let policy = CustomRetryPolicy::new(RetryConfig {
+            initial_backoff_ms: 1000,
+            jitter: 0.5,
+            ..Default::default()
+        });
+        for _ in 0..100 {
+            let delay = policy.backoff(0).as_millis();
+            assert!((500..=1500).contains(&delay), "{delay}");
+        }
+    }
+
+    #[test]
+    fn profile_is_chosen_by_url() {
+        let settings: RetrySettings = serde_json::from_str(
+            r#"{
+                "default": {"max_retries": 1},
+                "profiles": [{"pattern": "infura.io", "max_retries": 7, "rules": [{"code": -32005}]}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings
+                .for_url("https://mainnet.infura.io/v3/key")
+                .max_retries,
+            7
+        );
+        assert_eq!(settings.for_url("https://eth.llamarpc.com").max_retries, 1);
+        // unset fields fall back to the built-in defaults
+        assert_eq!(settings.for_url("https://eth.llamarpc.com").rules.len(), 5);
+    }
+}
@@ -4,4 +4,170 @@ use serde::{Deserialize, Serialize};
 pub struct Msg {
     pub start: u64,
     pub stop: u64,
+    /// 已经失败的次数
+    #[serde(default)]
+    pub attempt: u32,
+}
+
+impl Msg {
+    pub fn new(start: u64, stop: u64) -> Self {
+        Self {
+            start,
+            stop,
+            attempt: 0,
+        }
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.stop - self.start + 1
+    }
+
+    /// 对半拆分, 两半都从第0次尝试开始
+    pub fn split(self) -> (Self, Self) {
+        let mid = self.start + self.blocks() / 2;
+        (Self::new(self.start, mid - 1), Self::new(mid, self.stop))
+    }
+
+    pub fn retry(self) -> Self {
+        Self {
+            attempt: self.attempt + 1,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TxRow {
+    /// 日志所属的合约地址
+    pub contract: String,
+    pub tag_id: String,
+    pub hash: String,
+    pub log_index: u64,
+    pub block: u64,
+    pub block_hash: String,
+    /// message里的gen_time
+    pub log_time: String,
+    pub to_addr: String,
+    /// U256十进制字符串
+    pub value: String,
+    pub message: String,
+    /// 离链头还不到确认数的日志可能被回滚
+    pub confirmed: bool,
+}
+
+/// Transfer事件, from是零地址的是mint
+#[derive(Debug, Clone)]
+pub struct TransferRow {
+    /// 日志所属的合约地址
+    pub contract: String,
+    pub hash: String,
+    pub log_index: u64,
+    pub block: u64,
+    pub block_hash: String,
+    pub from_addr: String,
+    pub to_addr: String,
+    pub value: String,
+    pub confirmed: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApprovalRow {
+    /// 日志所属的合约地址
+    pub contract: String,
+    pub hash: String,
+    pub log_index: u64,
+    pub block: u64,
+    pub block_hash: String,
+    pub owner: String,
+    pub spender: String,
+    pub value: String,
+    pub confirmed: bool,
+}
+
+/// message解析失败的TokenTransfer日志, 保留原始内容方便排查
+#[derive(Debug, Clone)]
+pub struct MalformedRow {
+    pub contract: String,
+    pub hash: String,
+    pub log_index: u64,
+    pub block: u64,
+    pub block_hash: String,
+    pub message: String,
+    pub error: String,
+    pub confirmed: bool,
+}
+
+/// 扫描哪些事件
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EventSet {
+    /// 只查TokenTransfer
+    #[default]
+    TokenTransfer,
+    /// 查合约的全部日志, 按Erc20TokenEvents解码
+    All,
+}
+
+impl EventSet {
+    pub fn from_flag(all_events: bool) -> Self {
+        if all_events {
+            EventSet::All
+        } else {
+            EventSet::TokenTransfer
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventSet::TokenTransfer => "token_transfer",
+            EventSet::All => "all",
+        }
+    }
+}
+
+/// 一个区块范围扫描完成后的全部日志
+#[derive(Debug)]
+pub struct RangeResult {
+    pub range: Msg,
+    pub rows: Vec<TxRow>,
+    pub transfers: Vec<TransferRow>,
+    pub approvals: Vec<ApprovalRow>,
+    pub malformed: Vec<MalformedRow>,
+    /// 未确认的范围记下结束区块的hash, 用来发现分叉
+    pub stop_hash: Option<String>,
+}
+
+impl RangeResult {
+    pub fn logs(&self) -> usize {
+        self.rows.len() + self.transfers.len() + self.approvals.len() + self.malformed.len()
+    }
+
+    pub fn logs_of(&self, contract: &str) -> usize {
+        self.rows.iter().filter(|r| r.contract == contract).count()
+            + self
+                .transfers
+                .iter()
+                .filter(|r| r.contract == contract)
+                .count()
+            + self
+                .approvals
+                .iter()
+                .filter(|r| r.contract == contract)
+                .count()
+            + self
+                .malformed
+                .iter()
+                .filter(|r| r.contract == contract)
+                .count()
+    }
+}
+
+/// worker发给db task的消息
+#[derive(Debug)]
+pub enum DbMsg {
+    Done(RangeResult),
+    /// 重试次数用完仍然失败的范围
+    Failed {
+        range: Msg,
+        error: String,
+    },
 }
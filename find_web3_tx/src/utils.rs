@@ -1,74 +1,80 @@
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use ethers::prelude::{HttpClientError, JsonRpcError, RetryPolicy};
-use serde::Deserialize;
-
-#[derive(Debug, Default)]
-pub struct CustomRetryPolicy;
-
-impl RetryPolicy<HttpClientError> for CustomRetryPolicy {
-    fn should_retry(&self, error: &HttpClientError) -> bool {
-        fn should_retry_json_rpc_error(err: &JsonRpcError) -> bool {
-            let JsonRpcError { code, message, .. } = err;
-            // alchemy throws it this way
-            if *code == 429 {
-                return true;
-            }
+/// provider拒绝eth_getLogs是因为范围太大或结果太多, 而不是限流或网络错误
+pub fn is_range_limit_error(message: &str) -> bool {
+    // 不能只认"limit exceeded", bsc的公共节点限流时也回-32005 "limit exceeded"
+    const PATTERNS: [&str; 7] = [
+        "query returned more than",
+        "more than 10000 results",
+        "block range is too wide",
+        "block range too large",
+        "exceed maximum block range",
+        "range is too large",
+        "response size exceeded",
+    ];
+    let message = message.to_lowercase();
+    PATTERNS.iter().any(|p| message.contains(p))
+}
 
-            // This is an infura error code for `exceeded project rate limit`
-            if *code == -32005 {
-                return true;
-            }
+/// workers共享的eth_getLogs区块步长, 结果稀疏时加倍, provider报范围限制时减半
+#[derive(Debug)]
+pub struct AdaptiveStep {
+    step: AtomicU64,
+    min: u64,
+    max: u64,
+}
 
-            // alternative alchemy error for specific IPs
-            if *code == -32016 && message.contains("rate limit") {
-                return true;
-            }
+impl AdaptiveStep {
+    /// 结果数低于这个值认为稀疏
+    const SPARSE_LOGS: usize = 1000;
 
-            match message.as_str() {
-                // this is commonly thrown by infura and is apparently a load balancer issue, see also <https://github.com/MetaMask/metamask-extension/issues/7234>
-                "header not found" => true,
-                // also thrown by infura if out of budget for the day and ratelimited
-                "daily request count exceeded, request rate limited" => true,
-                _ => false,
-            }
+    pub fn new(step: u64, min: u64, max: u64) -> Self {
+        Self {
+            step: AtomicU64::new(step.clamp(min, max)),
+            min,
+            max,
         }
+    }
 
-        match error {
-            HttpClientError::ReqwestError(_) => true,
-            HttpClientError::JsonRpcError(err) => should_retry_json_rpc_error(err),
-            HttpClientError::SerdeJson { text, .. } => {
-                // some providers send invalid JSON RPC in the error case (no `id:u64`), but the
-                // text should be a `JsonRpcError`
-                #[derive(Deserialize)]
-                struct Resp {
-                    error: JsonRpcError,
-                }
+    pub fn get(&self) -> u64 {
+        self.step.load(Ordering::Relaxed)
+    }
 
-                if let Ok(resp) = serde_json::from_str::<Resp>(text) {
-                    return should_retry_json_rpc_error(&resp.error);
-                }
-                false
-            }
+    /// a full sized range came back with few logs
+    pub fn observe(&self, blocks: u64, logs: usize) {
+        if logs < Self::SPARSE_LOGS && blocks >= self.get() {
+            let _ = self
+                .step
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |s| {
+                    Some((s * 2).min(self.max))
+                });
         }
     }
 
-    fn backoff_hint(&self, error: &HttpClientError) -> Option<Duration> {
-        if let HttpClientError::JsonRpcError(JsonRpcError { data, .. }) = error {
-            let data = data.as_ref()?;
+    /// the provider rejected a range of `blocks`, returns the new step
+    pub fn shrink(&self, blocks: u64) -> u64 {
+        let half = (blocks / 2).max(self.min);
+        self.step.fetch_min(half, Ordering::Relaxed).min(half)
+    }
+}
 
-            // if daily rate limit exceeded, infura returns the requested backoff in the error
-            // response
-            let backoff_seconds = &data["rate"]["backoff_seconds"];
-            // infura rate limit error
-            if let Some(seconds) = backoff_seconds.as_u64() {
-                return Some(Duration::from_secs(seconds));
-            }
-            if let Some(seconds) = backoff_seconds.as_f64() {
-                return Some(Duration::from_secs(seconds as u64 + 1));
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_limit_messages() {
+        assert!(is_range_limit_error(
+            "query returned more than 10000 results"
+        ));
+        assert!(is_range_limit_error("Log response size exceeded."));
+        assert!(is_range_limit_error("block range is too wide"));
+    }
 
-        None
+    #[test]
+    fn rate_limit_is_not_range_limit() {
+        assert!(!is_range_limit_error("rate limit exceeded"));
+        assert!(!is_range_limit_error("limit exceeded"));
+        assert!(!is_range_limit_error("daily request count exceeded"));
     }
 }
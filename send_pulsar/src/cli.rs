@@ -0,0 +1,52 @@
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+
+use crate::dead_letter::SinkKind;
+use crate::source::TableName;
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Publish state-changing token calls of transaction_history to pulsar"
+)]
+pub struct Cli {
+    /// source table, repeat for more tables, defaults to transaction_history_1
+    #[arg(short = 't', long = "table", conflicts_with = "table_pattern")]
+    pub tables: Vec<TableName>,
+    /// LIKE pattern of the source tables looked up in information_schema, e.g. `transaction_history_%`
+    #[arg(long)]
+    pub table_pattern: Option<String>,
+    /// tables published at the same time, each with its own producer and cursor
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    pub parallel: u32,
+    /// rows fetched per page, the cursor is saved after every page is acked
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(i64).range(1..))]
+    pub page_size: i64,
+    /// first id to publish in every table, defaults to the one after the saved cursor
+    #[arg(long)]
+    pub start_id: Option<i64>,
+    /// last id to publish in every table, inclusive
+    #[arg(long)]
+    pub end_id: Option<i64>,
+    /// where rows that fail decoding go: `pg` for the send_pulsar_dead_letter table,
+    /// `topic:<topic>` or `file:<path>` for JSON lines; rows already dead-lettered are skipped on rerun
+    #[arg(long, default_value = "pg")]
+    pub dead_letter: SinkKind,
+}
+
+impl Cli {
+    pub fn parse_and_validate() -> Self {
+        let cli = Cli::parse();
+        if let (Some(start), Some(end)) = (cli.start_id, cli.end_id) {
+            if start > end {
+                Cli::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!("--start-id {start} is larger than --end-id {end}"),
+                    )
+                    .exit();
+            }
+        }
+        cli
+    }
+}
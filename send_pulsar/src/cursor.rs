@@ -0,0 +1,44 @@
+use tokio_postgres::Client;
+
+/// 每个来源表发到每个topic的进度, 记录broker已经确认的最后一个id
+pub const CURSOR_TABLE: &str = "send_pulsar_cursor";
+
+pub async fn ensure_table(conn: &Client) -> anyhow::Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {CURSOR_TABLE} (
+                source TEXT NOT NULL,
+                topic TEXT NOT NULL,
+                last_id BIGINT NOT NULL,
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                PRIMARY KEY (source, topic)
+            )"
+        ),
+        &[],
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn load(conn: &Client, source: &str, topic: &str) -> anyhow::Result<Option<i64>> {
+    let row = conn
+        .query_opt(
+            &format!("SELECT last_id FROM {CURSOR_TABLE} WHERE source=$1 AND topic=$2"),
+            &[&source, &topic],
+        )
+        .await?;
+    Ok(row.map(|row| row.get("last_id")))
+}
+
+/// 用--start-id重发旧的范围时不会把进度往回拨
+pub async fn save(conn: &Client, source: &str, topic: &str, last_id: i64) -> anyhow::Result<()> {
+    conn.execute(
+        &format!(
+            "INSERT INTO {CURSOR_TABLE}(source,topic,last_id) VALUES($1,$2,$3)
+             ON CONFLICT(source,topic) DO UPDATE SET last_id=GREATEST({CURSOR_TABLE}.last_id,excluded.last_id),updated_at=now()"
+        ),
+        &[&source, &topic, &last_id],
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,301 @@
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use pulsar::{Producer, Pulsar, TokioExecutor};
+use serde_json::json;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+/// 解析失败的行存到这个表, 已经在表里的行重跑时跳过, 删掉之后才会重新处理
+pub const DEAD_LETTER_TABLE: &str = "send_pulsar_dead_letter";
+
+/// topic和文件sink写出去的行记在这里, 重跑时跳过, 不会重复写死信;
+/// 写出去之后到记下来之前崩溃的话还是会重复一次
+pub const WRITTEN_TABLE: &str = "send_pulsar_dead_letter_written";
+
+/// 解析失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    /// tx_str不是合法的hex
+    Hex,
+    /// 不是Erc20TokenCalls能解出来的调用
+    Abi,
+    /// 能解出来但是只读的调用, 不用发
+    UnsupportedCall,
+    /// 调用参数里的json不是合法的TokenMessageArg
+    InvalidArg,
+}
+
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Hex => "hex",
+            Category::Abi => "abi",
+            Category::UnsupportedCall => "unsupported_call",
+            Category::InvalidArg => "invalid_arg",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ProcessError {
+    pub category: Category,
+    pub error: anyhow::Error,
+}
+
+impl ProcessError {
+    pub fn new(category: Category, error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            category,
+            error: error.into(),
+        }
+    }
+}
+
+impl Display for ProcessError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.category.as_str(), self.error)
+    }
+}
+
+/// 一条死信
+#[derive(Debug)]
+pub struct DeadLetter {
+    pub history_id: i64,
+    pub tx_str: String,
+    pub error: ProcessError,
+}
+
+/// 死信写到哪里
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkKind {
+    Postgres,
+    Topic(String),
+    File(PathBuf),
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "pg" => Ok(SinkKind::Postgres),
+            Some(("topic", topic)) if !topic.is_empty() => Ok(SinkKind::Topic(topic.to_string())),
+            Some(("file", path)) if !path.is_empty() => Ok(SinkKind::File(path.into())),
+            _ => Err(format!(
+                "invalid dead letter sink {s:?}, expected `pg`, `topic:<topic>` or `file:<path>`"
+            )),
+        }
+    }
+}
+
+/// 所有表共用一个sink, topic和文件各自加锁
+pub enum Sink {
+    Postgres,
+    Topic(Mutex<Producer<TokioExecutor>>),
+    File(Mutex<BufWriter<File>>),
+}
+
+impl Sink {
+    pub async fn open(
+        kind: &SinkKind,
+        pulsar: &Pulsar<TokioExecutor>,
+        conn: &Client,
+    ) -> anyhow::Result<Self> {
+        Ok(match kind {
+            SinkKind::Postgres => {
+                ensure_table(conn).await?;
+                Sink::Postgres
+            }
+            SinkKind::Topic(topic) => {
+                ensure_written_table(conn).await?;
+                let producer = pulsar.producer().with_topic(topic).build().await?;
+                Sink::Topic(Mutex::new(producer))
+            }
+            SinkKind::File(path) => {
+                ensure_written_table(conn).await?;
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Sink::File(Mutex::new(BufWriter::new(file)))
+            }
+        })
+    }
+
+    /// 已经写过死信的行记在哪个表, 读取时跳过
+    pub fn written_table(&self) -> &'static str {
+        match self {
+            Sink::Postgres => DEAD_LETTER_TABLE,
+            Sink::Topic(_) | Sink::File(_) => WRITTEN_TABLE,
+        }
+    }
+
+    /// 写完才返回, 调用方随后保存进度
+    pub async fn send(
+        &self,
+        conn: &Client,
+        source: &str,
+        letters: &[DeadLetter],
+    ) -> anyhow::Result<()> {
+        if letters.is_empty() {
+            return Ok(());
+        }
+        match self {
+            Sink::Postgres => {
+                let ids: Vec<i64> = letters.iter().map(|l| l.history_id).collect();
+                let categories: Vec<&str> =
+                    letters.iter().map(|l| l.error.category.as_str()).collect();
+                let errors: Vec<String> =
+                    letters.iter().map(|l| l.error.error.to_string()).collect();
+                let inputs: Vec<&str> = letters.iter().map(|l| l.tx_str.as_str()).collect();
+                conn.execute(
+                    &format!(
+                        "INSERT INTO {DEAD_LETTER_TABLE}(source,history_id,category,error,tx_str)
+                         SELECT $1,* FROM UNNEST($2::bigint[],$3::text[],$4::text[],$5::text[])
+                         ON CONFLICT(source,history_id) DO UPDATE
+                         SET category=excluded.category,error=excluded.error,tx_str=excluded.tx_str,created_at=now()"
+                    ),
+                    &[&source, &ids, &categories, &errors, &inputs],
+                )
+                .await?;
+            }
+            Sink::Topic(producer) => {
+                let mut producer = producer.lock().await;
+                let mut receipts = Vec::with_capacity(letters.len());
+                for letter in letters {
+                    let record = to_json(source, letter).to_string();
+                    receipts.push(producer.send_non_blocking(record).await?);
+                }
+                producer.send_batch().await?;
+                for receipt in receipts {
+                    receipt.await?;
+                }
+                record_written(conn, source, letters).await?;
+            }
+            Sink::File(writer) => {
+                let mut writer = writer.lock().await;
+                for letter in letters {
+                    serde_json::to_writer(&mut *writer, &to_json(source, letter))?;
+                    writer.write_all(b"\n")?;
+                }
+                writer.flush()?;
+                record_written(conn, source, letters).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn close(&self) -> anyhow::Result<()> {
+        if let Sink::Topic(producer) = self {
+            producer.lock().await.close().await?;
+        }
+        Ok(())
+    }
+}
+
+fn to_json(source: &str, letter: &DeadLetter) -> serde_json::Value {
+    json!({
+        "source": source,
+        "history_id": letter.history_id,
+        "category": letter.error.category.as_str(),
+        "error": letter.error.error.to_string(),
+        "tx_str": letter.tx_str,
+    })
+}
+
+async fn ensure_table(conn: &Client) -> anyhow::Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {DEAD_LETTER_TABLE} (
+                source TEXT NOT NULL,
+                history_id BIGINT NOT NULL,
+                category TEXT NOT NULL,
+                error TEXT NOT NULL,
+                tx_str TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                PRIMARY KEY (source, history_id)
+            )"
+        ),
+        &[],
+    )
+    .await?;
+    Ok(())
+}
+
+async fn ensure_written_table(conn: &Client) -> anyhow::Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {WRITTEN_TABLE} (
+                source TEXT NOT NULL,
+                history_id BIGINT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                PRIMARY KEY (source, history_id)
+            )"
+        ),
+        &[],
+    )
+    .await?;
+    Ok(())
+}
+
+async fn record_written(conn: &Client, source: &str, letters: &[DeadLetter]) -> anyhow::Result<()> {
+    let ids: Vec<i64> = letters.iter().map(|l| l.history_id).collect();
+    conn.execute(
+        &format!(
+            "INSERT INTO {WRITTEN_TABLE}(source,history_id)
+             SELECT $1,* FROM UNNEST($2::bigint[])
+             ON CONFLICT DO NOTHING"
+        ),
+        &[&source, &ids],
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sink_kind() {
+        assert_eq!("pg".parse(), Ok(SinkKind::Postgres));
+        assert_eq!(
+            "topic:persistent://public/default/dead".parse(),
+            Ok(SinkKind::Topic(
+                "persistent://public/default/dead".to_string()
+            ))
+        );
+        assert_eq!(
+            "file:/var/log/dead.jsonl".parse(),
+            Ok(SinkKind::File("/var/log/dead.jsonl".into()))
+        );
+        // 只按第一个冒号拆分
+        assert_eq!(
+            "file:C:\\dead.jsonl".parse(),
+            Ok(SinkKind::File("C:\\dead.jsonl".into()))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_sink_kind() {
+        for s in [
+            "",
+            "PG",
+            "pg:",
+            "postgres",
+            "topic",
+            "topic:",
+            "file",
+            "file:",
+            "kafka:dead",
+        ] {
+            assert_eq!(
+                s.parse::<SinkKind>(),
+                Err(format!(
+                    "invalid dead letter sink {s:?}, expected `pg`, `topic:<topic>` or `file:<path>`"
+                )),
+            );
+        }
+    }
+}
@@ -1,92 +1,548 @@
 use chrono::Local;
 use ethers::abi::AbiDecode;
-use ethers::prelude::Bytes;
-use futures_util::{pin_mut, StreamExt};
-use log::info;
-use pulsar::{ProducerOptions, Pulsar, TokioExecutor};
+use ethers::prelude::{Address, Bytes};
+use log::{debug, error, info, warn};
+use pulsar::proto::CommandSendReceipt;
+use pulsar::{Producer, ProducerOptions, Pulsar, TokioExecutor};
+use std::collections::BTreeMap;
 use std::str::FromStr;
-use std::sync::OnceLock;
-use tokio::join;
-use tokio_postgres::types::ToSql;
-use tokio_postgres::Row;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_postgres::{Client, Row};
 
 use common::erc20::Erc20TokenCalls;
-use common::schema::{Msg, PulsarSchema, TokenMessageArg};
-use common::{create_pool, init_logger, Setting};
+use common::schema::{
+    ApproveMsg, BatchMintMsg, CallMsg, PulsarSchema, TokenMessageArg, TransferFromMsg, TransferMsg,
+};
+use common::shutdown::Shutdown;
+use common::{create_pool, init_logger, metrics, Setting};
+
+use crate::cli::Cli;
+use crate::dead_letter::{Category, DeadLetter, ProcessError, Sink};
+use crate::source::TableName;
+
+mod cli;
+mod cursor;
+mod dead_letter;
+mod outbox;
+mod source;
 
 static SETTING: OnceLock<Setting> = OnceLock::new();
 
+/// 没有指定--table和--table-pattern时的来源表
+const DEFAULT_TABLE: &str = "transaction_history_1";
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse_and_validate();
     let setting = SETTING.get_or_init(Setting::init);
     init_logger();
-    let (chs, chr) = async_channel::unbounded::<Msg>();
+    if let Some(addr) = &setting.metrics_addr {
+        metrics::serve(addr).await?;
+    }
     let pool = create_pool(&setting.explorer_db).await;
     let now = Local::now();
 
     let pulsar: Pulsar<TokioExecutor> = Pulsar::builder(&setting.pulsar_addr, TokioExecutor)
         .build()
         .await?;
+    let conn = pool.get().await?;
+    cursor::ensure_table(&conn).await?;
+    outbox::ensure_table(&conn).await?;
+    let tables = match &cli.table_pattern {
+        Some(pattern) => {
+            let tables = source::discover(&conn, pattern).await?;
+            if tables.is_empty() {
+                return Err(format!("no table matches {pattern:?}").into());
+            }
+            tables
+        }
+        None if cli.tables.is_empty() => vec![DEFAULT_TABLE.parse()?],
+        None => {
+            source::check_exist(&conn, &cli.tables).await?;
+            cli.tables.clone()
+        }
+    };
+    let sink = Arc::new(Sink::open(&cli.dead_letter, &pulsar, &conn).await?);
+    drop(conn);
+    info!(
+        "publish {} tables to {}, parallel={}, page_size={}",
+        tables.len(),
+        setting.topic,
+        cli.parallel,
+        cli.page_size
+    );
+
+    // 每个表一个producer和一个进度, 互不影响, 一个表失败不会停掉其它表
+    let shutdown = Shutdown::listen();
+    let options = PageOptions {
+        start_id: cli.start_id,
+        end_id: cli.end_id.unwrap_or(i64::MAX),
+        page_size: cli.page_size,
+    };
+    let semaphore = Arc::new(Semaphore::new(cli.parallel as usize));
+    let mut tasks = JoinSet::new();
+    for table in tables {
+        let pulsar = pulsar.clone();
+        let pool = pool.clone();
+        let shutdown = shutdown.clone();
+        let semaphore = semaphore.clone();
+        let sink = sink.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore
+                .acquire_owned()
+                .await
+                .expect("semaphore never closed");
+            let result = async {
+                let conn = pool.get().await?;
+                publish_table(&pulsar, &conn, &table, options, &sink, &shutdown).await
+            }
+            .await;
+            (table, result)
+        });
+    }
+
+    let mut total = Summary::default();
+    let mut failed = Vec::new();
+    while let Some(res) = tasks.join_next().await {
+        let (table, result) = res?;
+        match result {
+            Ok(summary) => {
+                info!(
+                    "{table}: read {} rows, {} sent, {}, last id {}",
+                    summary.read,
+                    summary.sent,
+                    summary.dead_letter_counts(),
+                    summary.last_id
+                );
+                total.add(&summary);
+            }
+            Err(e) => {
+                error!("{table}: {e:#}");
+                failed.push(table);
+            }
+        }
+    }
+    sink.close().await?;
+    info!(
+        "{}read {} rows, {} sent, {}, run time: {}s",
+        if shutdown.is_triggered() {
+            "interrupted, "
+        } else {
+            ""
+        },
+        total.read,
+        total.sent,
+        total.dead_letter_counts(),
+        Local::now().signed_duration_since(now).num_seconds()
+    );
+    if !failed.is_empty() {
+        let names: Vec<&str> = failed.iter().map(TableName::as_str).collect();
+        return Err(format!("tables failed: {}, rerun to resume", names.join(", ")).into());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PageOptions {
+    /// 不指定时从保存的进度开始
+    start_id: Option<i64>,
+    end_id: i64,
+    page_size: i64,
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    read: usize,
+    sent: usize,
+    dead_letters: BTreeMap<Category, usize>,
+    last_id: i64,
+}
+
+impl Summary {
+    fn add(&mut self, other: &Summary) {
+        self.read += other.read;
+        self.sent += other.sent;
+        for (category, count) in &other.dead_letters {
+            *self.dead_letters.entry(*category).or_default() += count;
+        }
+    }
+
+    /// 例如`3 dead letters (abi 1, invalid_arg 2)`
+    fn dead_letter_counts(&self) -> String {
+        let total: usize = self.dead_letters.values().sum();
+        if total == 0 {
+            return "0 dead letters".to_string();
+        }
+        let counts: Vec<String> = self
+            .dead_letters
+            .iter()
+            .map(|(category, count)| format!("{} {count}", category.as_str()))
+            .collect();
+        format!("{total} dead letters ({})", counts.join(", "))
+    }
+}
+
+/// 按id分页发送一个表里还没有发过的行, 一页全部确认后才保存进度;
+/// 收到信号后不再读新的一页
+async fn publish_table(
+    pulsar: &Pulsar<TokioExecutor>,
+    conn: &Client,
+    table: &TableName,
+    options: PageOptions,
+    sink: &Sink,
+    shutdown: &Shutdown,
+) -> anyhow::Result<Summary> {
+    let topic = &SETTING.get().expect("setting initialized").topic;
     let mut producer = pulsar
         .producer()
-        .with_topic(&setting.topic)
+        .with_topic(topic)
+        // 同一个表同时只能有一个进程在发, 第二个同名producer会被broker拒绝
+        .with_name(format!("send_pulsar-{table}"))
         .with_options(ProducerOptions {
             // compression: Some(Compression::Lz4(CompressionLz4::default())),
-            schema: Some(Msg::pulsar_json_schema()),
+            schema: Some(CallMsg::pulsar_json_schema()),
             batch_size: Some(1000),
             ..Default::default()
         })
         .build()
         .await?;
-
-    let pulsar_task = tokio::spawn(async move {
-        while let Ok(msg) = chr.recv().await {
-            producer.send_non_blocking(msg).await?;
+    // 自增id从1开始, 没有保存过进度时从头发
+    let mut summary = Summary {
+        last_id: match options.start_id {
+            Some(id) => id - 1,
+            None => cursor::load(conn, table.as_str(), topic)
+                .await?
+                .unwrap_or(0),
+        },
+        ..Default::default()
+    };
+    info!(
+        "{table}: publish from id {} to {}",
+        summary.last_id + 1,
+        options.end_id
+    );
+    let query = format!(
+        "SELECT h.id::bigint AS id, h.tx_str FROM {} h
+         WHERE h.id > $1::bigint AND h.id <= $2::bigint AND NOT EXISTS (
+             SELECT 1 FROM {} p WHERE p.source=$4 AND p.topic=$5 AND p.history_id=h.id
+         ) AND NOT EXISTS (
+             SELECT 1 FROM {} d WHERE d.source=$4 AND d.history_id=h.id
+         )
+         ORDER BY h.id LIMIT $3",
+        table.quoted(),
+        outbox::OUTBOX_TABLE,
+        sink.written_table()
+    );
+    while !shutdown.is_triggered() {
+        let rows = conn
+            .query(
+                &query,
+                &[
+                    &summary.last_id,
+                    &options.end_id,
+                    &options.page_size,
+                    &table.as_str(),
+                    topic,
+                ],
+            )
+            .await?;
+        let Some(page_last) = rows.last().map(|row| row.get::<_, i64>("id")) else {
+            break;
+        };
+        let full_page = rows.len() as i64 == options.page_size;
+        publish_page(&mut producer, conn, table, topic, rows, sink, &mut summary).await?;
+        cursor::save(conn, table.as_str(), topic, page_last).await?;
+        summary.last_id = page_last;
+        info!(
+            "{table}: published up to id {page_last}, {} sent",
+            summary.sent
+        );
+        if !full_page {
+            break;
         }
-        info!("pulsar_task exit");
-        producer.send_batch().await?;
-        producer.close().await?;
-        Ok::<(), anyhow::Error>(())
-    });
-
-    let params: [&(dyn ToSql + Sync); 0] = [];
-    let conn = pool.get().await?;
-    let rows = conn
-        .query_raw(
-            "SELECT id, tx_str FROM transaction_history_1 ORDER BY id limit 100;",
-            params,
-        )
-        .await?;
-    pin_mut!(rows);
-    while let Some(Ok(row)) = rows.next().await {
-        let chs = chs.clone();
-        tokio::spawn(async move {
-            let msg = process(row);
-            if let Ok(msg) = msg {
-                chs.send(msg).await?;
-            };
-            Ok::<(), anyhow::Error>(())
-        });
     }
+    producer.close().await?;
+    Ok(summary)
+}
 
-    drop(chs);
-    let _ = join!(pulsar_task);
-    info!(
-        "run time: {}s",
-        Local::now().signed_duration_since(now).num_seconds()
-    );
+/// 发送一页并等broker确认, 确认过的行先写进outbox再处理失败的, 解析失败的行写到死信;
+/// 有消息失败时返回错误, 进度停在上一页, 重跑时只会重发失败的行.
+/// 中途发送出错时也先等已经发出去的消息确认完并记下来, 不然它们下次会被重发
+async fn publish_page(
+    producer: &mut Producer<TokioExecutor>,
+    conn: &Client,
+    table: &TableName,
+    topic: &str,
+    rows: Vec<Row>,
+    sink: &Sink,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let mut receipts = Vec::with_capacity(rows.len());
+    let mut letters = Vec::new();
+    let mut send_err = None;
+    for row in rows {
+        summary.read += 1;
+        let id: i64 = row.get("id");
+        let tx_str: String = row.get("tx_str");
+        match process(&tx_str) {
+            Ok(msg) => match producer.send_non_blocking(msg).await {
+                Ok(receipt) => receipts.push((id, receipt)),
+                Err(e) => {
+                    metrics::pulsar_failed(1);
+                    send_err = Some(e);
+                    break;
+                }
+            },
+            Err(error) => {
+                debug!("dead letter id {id}: {error}");
+                *summary.dead_letters.entry(error.category).or_default() += 1;
+                letters.push(DeadLetter {
+                    history_id: id,
+                    tx_str,
+                    error,
+                });
+            }
+        }
+    }
+    if let Err(e) = producer.send_batch().await {
+        // 批里的消息各自的receipt也会失败, 下面照常处理
+        warn!("pulsar send batch error: {e}");
+    }
+    let mut failed = 0;
+    let mut published = Vec::with_capacity(receipts.len());
+    for (id, receipt) in receipts {
+        match receipt.await {
+            Ok(CommandSendReceipt {
+                message_id: Some(message_id),
+                ..
+            }) => {
+                summary.sent += 1;
+                metrics::pulsar_sent(1);
+                published.push((id, message_id));
+            }
+            Ok(_) => {
+                failed += 1;
+                metrics::pulsar_failed(1);
+                warn!("pulsar receipt without message id, id {id}");
+            }
+            Err(e) => {
+                failed += 1;
+                metrics::pulsar_failed(1);
+                warn!("pulsar send error, id {id}: {e}");
+            }
+        }
+    }
+    // 确认之后到这里写完之前崩溃的话, 这些行下次还会再发一次,
+    // producer名字固定, namespace开了去重时broker可以挡掉同一个producer重连后的重发
+    outbox::record(conn, table.as_str(), topic, &published).await?;
+    sink.send(conn, table.as_str(), &letters).await?;
+    if let Some(e) = send_err {
+        return Err(e.into());
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} messages failed, rerun to resend them");
+    }
     Ok(())
 }
 
-fn process(row: Row) -> Result<Msg, anyhow::Error> {
-    let input: String = row.get("tx_str");
-    let b_input = Bytes::from_str(&input)?;
-    let decode_input = Erc20TokenCalls::decode(b_input)?;
-    if let Erc20TokenCalls::TokenTransfer(v) = decode_input {
-        let args: TokenMessageArg = serde_json::from_str(&v.message)?;
-        let msg = args.make_msg_with_ext(v.message);
-        Ok(msg)
-    } else {
-        Err(anyhow::anyhow!("parsed error"))
+/// 会改变状态的调用都发, 只读的调用进死信
+fn process(input: &str) -> Result<CallMsg, ProcessError> {
+    let b_input = Bytes::from_str(input).map_err(|e| ProcessError::new(Category::Hex, e))?;
+    let decode_input =
+        Erc20TokenCalls::decode(b_input).map_err(|e| ProcessError::new(Category::Abi, e))?;
+    let msg = match decode_input {
+        Erc20TokenCalls::TokenTransfer(v) => {
+            let args: TokenMessageArg = serde_json::from_str(&v.message)
+                .map_err(|e| ProcessError::new(Category::InvalidArg, e))?;
+            CallMsg::TokenTransfer(args.make_msg_with_ext(v.message))
+        }
+        Erc20TokenCalls::Transfer(v) => CallMsg::Transfer(TransferMsg {
+            to: hex_address(v.to),
+            amount: v.amount.to_string(),
+        }),
+        Erc20TokenCalls::TransferFrom(v) => CallMsg::TransferFrom(TransferFromMsg {
+            from: hex_address(v.from),
+            to: hex_address(v.to),
+            amount: v.amount.to_string(),
+        }),
+        Erc20TokenCalls::Mint(v) => CallMsg::Mint(TransferMsg {
+            to: hex_address(v.to),
+            amount: v.amount.to_string(),
+        }),
+        Erc20TokenCalls::BatchMint(v) => CallMsg::BatchMint(BatchMintMsg {
+            recipients: v.to.into_iter().map(hex_address).collect(),
+            amount: v.amount.to_string(),
+        }),
+        Erc20TokenCalls::Approve(v) => CallMsg::Approve(ApproveMsg {
+            spender: hex_address(v.spender),
+            amount: v.amount.to_string(),
+        }),
+        Erc20TokenCalls::IncreaseAllowance(v) => CallMsg::IncreaseAllowance(ApproveMsg {
+            spender: hex_address(v.spender),
+            amount: v.added_value.to_string(),
+        }),
+        Erc20TokenCalls::DecreaseAllowance(v) => CallMsg::DecreaseAllowance(ApproveMsg {
+            spender: hex_address(v.spender),
+            amount: v.subtracted_value.to_string(),
+        }),
+        other => {
+            return Err(ProcessError::new(
+                Category::UnsupportedCall,
+                anyhow::anyhow!("read-only call {other:?}"),
+            ))
+        }
+    };
+    Ok(msg)
+}
+
+/// 完整的0x小写hex, Display会把中间省略掉
+fn hex_address(address: Address) -> String {
+    format!("{address:?}")
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::abi::AbiEncode;
+    use ethers::prelude::U256;
+    use serde_json::json;
+
+    use common::erc20::*;
+
+    use super::*;
+
+    const MESSAGE: &str = r#"{"from_user_id":"u1","to_user_id":"u2","coin_code":"PT","point":1.5,"tag_id":"t1","store_id":"s1","gen_time":"2024-01-02 09:00:00","trxn_result":"ok"}"#;
+
+    fn input(call: Erc20TokenCalls) -> String {
+        Bytes::from(call.encode()).to_string()
+    }
+
+    fn address(n: u64) -> Address {
+        Address::from_low_u64_be(n)
+    }
+
+    #[test]
+    fn calls_map_to_kinds() {
+        let (a1, a2) = (
+            "0x0000000000000000000000000000000000000001",
+            "0x0000000000000000000000000000000000000002",
+        );
+        let cases = [
+            (
+                Erc20TokenCalls::TokenTransfer(TokenTransferCall {
+                    to: address(1),
+                    amount: U256::from(15),
+                    message: MESSAGE.to_string(),
+                }),
+                json!({
+                    "kind": "token_transfer",
+                    "from_user_id": "u1",
+                    "to_user_id": "u2",
+                    "coin_code": "PT",
+                    "point": 1.5,
+                    "tag_id": "t1",
+                    "store_id": "s1",
+                    "gen_time": "2024-01-02 09:00:00",
+                    "ext_json": MESSAGE,
+                    "retry_info": null,
+                }),
+            ),
+            (
+                Erc20TokenCalls::Transfer(TransferCall {
+                    to: address(1),
+                    amount: U256::from(10).pow(U256::from(30)),
+                }),
+                json!({"kind": "transfer", "to": a1, "amount": "1000000000000000000000000000000"}),
+            ),
+            (
+                Erc20TokenCalls::TransferFrom(TransferFromCall {
+                    from: address(1),
+                    to: address(2),
+                    amount: U256::from(3),
+                }),
+                json!({"kind": "transfer_from", "from": a1, "to": a2, "amount": "3"}),
+            ),
+            (
+                Erc20TokenCalls::Mint(MintCall {
+                    to: address(2),
+                    amount: U256::from(4),
+                }),
+                json!({"kind": "mint", "to": a2, "amount": "4"}),
+            ),
+            (
+                Erc20TokenCalls::BatchMint(BatchMintCall {
+                    to: vec![address(1), address(2)],
+                    amount: U256::from(5),
+                }),
+                json!({"kind": "batch_mint", "recipients": [a1, a2], "amount": "5"}),
+            ),
+            (
+                Erc20TokenCalls::Approve(ApproveCall {
+                    spender: address(1),
+                    amount: U256::MAX,
+                }),
+                json!({"kind": "approve", "spender": a1, "amount": U256::MAX.to_string()}),
+            ),
+            (
+                Erc20TokenCalls::IncreaseAllowance(IncreaseAllowanceCall {
+                    spender: address(1),
+                    added_value: U256::from(6),
+                }),
+                json!({"kind": "increase_allowance", "spender": a1, "amount": "6"}),
+            ),
+            (
+                Erc20TokenCalls::DecreaseAllowance(DecreaseAllowanceCall {
+                    spender: address(2),
+                    subtracted_value: U256::from(7),
+                }),
+                json!({"kind": "decrease_allowance", "spender": a2, "amount": "7"}),
+            ),
+        ];
+        for (call, expected) in cases {
+            let msg = process(&input(call)).unwrap();
+            assert_eq!(msg.kind(), expected["kind"]);
+            assert_eq!(serde_json::to_value(&msg).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn failures_map_to_categories() {
+        let cases = [
+            ("0xzz".to_string(), Category::Hex),
+            ("0x123".to_string(), Category::Hex),
+            ("0x".to_string(), Category::Abi),
+            ("0xdeadbeef".to_string(), Category::Abi),
+            (
+                input(Erc20TokenCalls::BalanceOf(BalanceOfCall {
+                    account: address(1),
+                })),
+                Category::UnsupportedCall,
+            ),
+            (
+                input(Erc20TokenCalls::TotalSupply(TotalSupplyCall)),
+                Category::UnsupportedCall,
+            ),
+            (
+                input(Erc20TokenCalls::TokenTransfer(TokenTransferCall {
+                    to: address(1),
+                    amount: U256::one(),
+                    message: "not json".to_string(),
+                })),
+                Category::InvalidArg,
+            ),
+            (
+                input(Erc20TokenCalls::TokenTransfer(TokenTransferCall {
+                    to: address(1),
+                    amount: U256::one(),
+                    message: r#"{"tag_id":"t1"}"#.to_string(),
+                })),
+                Category::InvalidArg,
+            ),
+        ];
+        for (input, category) in cases {
+            let error = process(&input).unwrap_err();
+            assert_eq!(error.category, category, "{input}: {error}");
+        }
     }
 }
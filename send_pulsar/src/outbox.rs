@@ -0,0 +1,54 @@
+use pulsar::proto::MessageIdData;
+use tokio_postgres::Client;
+
+/// 每个来源表的每一行发到每个topic的记录, broker确认后才写入,
+/// 读取时跳过已经在这里的行, 重跑和崩溃后重发都不会重复发送
+pub const OUTBOX_TABLE: &str = "send_pulsar_published";
+
+pub async fn ensure_table(conn: &Client) -> anyhow::Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {OUTBOX_TABLE} (
+                source TEXT NOT NULL,
+                topic TEXT NOT NULL,
+                history_id BIGINT NOT NULL,
+                ledger_id BIGINT NOT NULL,
+                entry_id BIGINT NOT NULL,
+                partition INT,
+                batch_index INT,
+                published_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                PRIMARY KEY (source, topic, history_id)
+            )"
+        ),
+        &[],
+    )
+    .await?;
+    Ok(())
+}
+
+/// 记录一批已经被broker确认的行, 重复记录时保留第一次的MessageId
+pub async fn record(
+    conn: &Client,
+    source: &str,
+    topic: &str,
+    published: &[(i64, MessageIdData)],
+) -> anyhow::Result<()> {
+    if published.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<i64> = published.iter().map(|(id, _)| *id).collect();
+    let ledgers: Vec<i64> = published.iter().map(|(_, m)| m.ledger_id as i64).collect();
+    let entries: Vec<i64> = published.iter().map(|(_, m)| m.entry_id as i64).collect();
+    let partitions: Vec<Option<i32>> = published.iter().map(|(_, m)| m.partition).collect();
+    let batches: Vec<Option<i32>> = published.iter().map(|(_, m)| m.batch_index).collect();
+    conn.execute(
+        &format!(
+            "INSERT INTO {OUTBOX_TABLE}(source,topic,history_id,ledger_id,entry_id,partition,batch_index)
+             SELECT $1,$2,* FROM UNNEST($3::bigint[],$4::bigint[],$5::bigint[],$6::int[],$7::int[])
+             ON CONFLICT DO NOTHING"
+        ),
+        &[&source, &topic, &ids, &ledgers, &entries, &partitions, &batches],
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use tokio_postgres::Client;
+
+use crate::{cursor, dead_letter, outbox};
+
+/// postgres标识符的长度上限
+const MAX_IDENT_LEN: usize = 63;
+
+/// 工具自己记录进度的表, 模式写得太宽时也不能当成来源表
+const OWN_TABLES: [&str; 4] = [
+    cursor::CURSOR_TABLE,
+    outbox::OUTBOX_TABLE,
+    dead_letter::DEAD_LETTER_TABLE,
+    dead_letter::WRITTEN_TABLE,
+];
+
+/// 校验过的表名, 只允许字母数字和下划线, 拼进sql时再加双引号
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TableName(String);
+
+impl TableName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn quoted(&self) -> String {
+        format!("\"{}\"", self.0)
+    }
+}
+
+impl FromStr for TableName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && s.len() <= MAX_IDENT_LEN;
+        if valid {
+            Ok(Self(s.to_string()))
+        } else {
+            Err(format!(
+                "invalid table name {s:?}, expected letters, digits and underscores"
+            ))
+        }
+    }
+}
+
+impl Display for TableName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// 当前schema里名字匹配LIKE模式的表, 按名字排序, 不包括工具自己的表
+pub async fn discover(conn: &Client, pattern: &str) -> anyhow::Result<Vec<TableName>> {
+    let rows = conn
+        .query(
+            "SELECT table_name::text FROM information_schema.tables WHERE table_schema=current_schema() AND table_type='BASE TABLE' AND table_name LIKE $1 ORDER BY table_name",
+            &[&pattern],
+        )
+        .await?;
+    sources(rows.iter().map(|row| row.get(0)))
+}
+
+fn sources(names: impl IntoIterator<Item = String>) -> anyhow::Result<Vec<TableName>> {
+    names
+        .into_iter()
+        .filter(|name| !OWN_TABLES.contains(&name.as_str()))
+        .map(|name| name.parse().map_err(|e: String| anyhow::anyhow!(e)))
+        .collect()
+}
+
+/// 命令行给的表必须都存在, 避免拼错的表名静默地什么都不发
+pub async fn check_exist(conn: &Client, tables: &[TableName]) -> anyhow::Result<()> {
+    let names: Vec<&str> = tables.iter().map(TableName::as_str).collect();
+    let rows = conn
+        .query(
+            "SELECT table_name::text FROM information_schema.tables WHERE table_schema=current_schema() AND table_name = ANY($1)",
+            &[&names],
+        )
+        .await?;
+    let found: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+    let missing: Vec<&str> = names
+        .into_iter()
+        .filter(|name| !found.iter().any(|f| f == name))
+        .collect();
+    anyhow::ensure!(
+        missing.is_empty(),
+        "tables not found: {}",
+        missing.join(", ")
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_table_names() {
+        for name in [
+            "transaction_history_1",
+            "_tmp",
+            "T2",
+            &"a".repeat(MAX_IDENT_LEN),
+        ] {
+            assert_eq!(name.parse::<TableName>().unwrap().as_str(), name);
+        }
+        let table: TableName = "transaction_history_1".parse().unwrap();
+        assert_eq!(table.quoted(), "\"transaction_history_1\"");
+    }
+
+    #[test]
+    fn invalid_table_names() {
+        for name in [
+            "",
+            "1table",
+            "a-b",
+            "a b",
+            "a\"b",
+            "public.t",
+            "t;drop table x",
+            "表",
+            &"a".repeat(MAX_IDENT_LEN + 1),
+        ] {
+            assert!(name.parse::<TableName>().is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn discover_skips_own_tables() {
+        let names = [
+            "send_pulsar_cursor",
+            "send_pulsar_dead_letter",
+            "send_pulsar_dead_letter_written",
+            "send_pulsar_published",
+            "send_pulsar_queue",
+            "transaction_history_1",
+        ];
+        let tables = sources(names.map(String::from)).unwrap();
+        let tables: Vec<&str> = tables.iter().map(TableName::as_str).collect();
+        assert_eq!(tables, ["send_pulsar_queue", "transaction_history_1"]);
+    }
+
+    #[test]
+    fn discover_rejects_invalid_names() {
+        assert!(sources(["transaction-history".to_string()]).is_err());
+    }
+}